@@ -0,0 +1,528 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, info, warn};
+
+use crate::Command;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CalendarConfig {
+    /// Path or `http://`/`https://` URL of an `.ics` file.
+    /// Events repeating daily or weekly by their `RRULE` are repeated as far ahead as the schedule is searched,
+    /// including their `INTERVAL`, `COUNT`, `UNTIL`, `BYDAY` and `EXDATE`.
+    /// Other recurring events only count on their first occurrence, which is warned about.
+    /// An occurrence that was moved counts at both its original and its new time.
+    pub source: String,
+    /// What the events in the calendar mean for the schedule
+    #[serde(default)]
+    pub mode: CalendarMode,
+    /// How often the calendar is fetched again
+    #[serde(default = "default_refresh_seconds")]
+    pub refresh_seconds: u64,
+}
+
+const fn default_refresh_seconds() -> u64 {
+    15 * 60
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CalendarMode {
+    /// Nothing plays during events, e.g. meetings
+    #[default]
+    Busy,
+    /// Sounds may also play during events, even outside of the schedule's windows
+    Extra,
+}
+
+/// An event in the calendar, ending right before `end`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    pub start: EventTime,
+    pub end: EventTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventTime {
+    Utc(DateTime<Utc>),
+    /// A time without a timezone, which is in the timezone of the schedule
+    Local(NaiveDateTime),
+}
+
+/// Reads the events in the calendar, or logs why it couldn't
+pub async fn load(config: &CalendarConfig) -> Option<Vec<Event>> {
+    match fetch(&config.source).await {
+        Ok(contents) => {
+            let events = parse(&contents, Utc::now());
+            debug!("Read {} events from calendar", events.len());
+            Some(events)
+        }
+        Err(e) => {
+            warn!("Could not read calendar {}: {e}", config.source);
+            None
+        }
+    }
+}
+
+/// Reads the calendar again every `refresh_seconds` and hands its events to the main loop
+pub async fn serve(config: CalendarConfig, commands: Sender<Command>) {
+    info!(
+        "Refreshing calendar {} every {} seconds",
+        config.source, config.refresh_seconds
+    );
+    let period = Duration::from_secs(config.refresh_seconds.max(1));
+    // It was already read once at startup
+    let mut refresh = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        refresh.tick().await;
+        let Some(events) = load(&config).await else {
+            continue;
+        };
+        if commands.send(Command::Calendar(events)).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn fetch(source: &str) -> anyhow::Result<String> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let response = reqwest::get(source).await?.error_for_status()?;
+        Ok(response.text().await?)
+    } else {
+        Ok(tokio::fs::read_to_string(source).await?)
+    }
+}
+
+/// Parses the events out of an iCalendar file that are within a year of `now`, repeating recurring events.
+/// Events that can't be understood are left out.
+fn parse(contents: &str, now: DateTime<Utc>) -> Vec<Event> {
+    // Long lines are folded by starting the continuation with a space or tab
+    let mut lines: Vec<String> = vec![];
+    for line in contents.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_owned()),
+        }
+    }
+
+    // As far as the schedule searches for valid time in either direction
+    let search = TimeDelta::days(crate::MAX_SEARCH_DAYS as i64);
+    let (from, horizon) = ((now - search).naive_utc(), (now + search).naive_utc());
+    let mut events = vec![];
+    let mut event: Option<Properties> = None;
+    for line in &lines {
+        let Some((property, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, params) = property.split_once(';').unwrap_or((property, ""));
+        match (name.to_ascii_uppercase().as_str(), &mut event) {
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VEVENT") => {
+                event = Some(Properties::default());
+            }
+            ("END", Some(properties)) if value.eq_ignore_ascii_case("VEVENT") => {
+                events.extend(properties.occurrences(from, horizon));
+                event = None;
+            }
+            ("DTSTART", Some(properties)) => {
+                properties.start = Time::parse(params, value);
+                properties.all_day = value.len() == 8;
+            }
+            ("DTEND", Some(properties)) => properties.end = Time::parse(params, value),
+            ("RRULE", Some(properties)) => properties.rule = Some(value.to_owned()),
+            ("EXDATE", Some(properties)) => properties.excluded.extend(
+                value
+                    .split(',')
+                    .filter_map(|value| Time::parse(params, value)),
+            ),
+            _ => {}
+        }
+    }
+    events
+}
+
+/// The properties of a `VEVENT` that are needed to know when it takes place
+#[derive(Debug, Default)]
+struct Properties {
+    start: Option<Time>,
+    end: Option<Time>,
+    all_day: bool,
+    /// The `RRULE` of a recurring event
+    rule: Option<String>,
+    /// Start of occurrences of a recurring event that don't take place, from `EXDATE`
+    excluded: Vec<Time>,
+}
+
+impl Properties {
+    /// Returns the occurrences of the event that end after `from`, ending with the last one starting before `horizon`
+    fn occurrences(&self, from: NaiveDateTime, horizon: NaiveDateTime) -> Vec<Event> {
+        let (start, end) = match (self.start, self.end) {
+            (Some(start), Some(end)) => (start, end),
+            // An all-day event without an end lasts for that day
+            (Some(start), None) if self.all_day => (start, start.shifted(1)),
+            _ => {
+                debug!("Ignoring calendar event without start and end");
+                return Vec::new();
+            }
+        };
+
+        let offsets = match self.rule.as_deref().map(Rule::parse) {
+            None => vec![0],
+            Some(Ok(rule)) => rule.day_offsets(start, horizon),
+            Some(Err(e)) => {
+                warn!(
+                    "Only counting the first occurrence of calendar event at {}: {e}",
+                    start.time
+                );
+                vec![0]
+            }
+        };
+        offsets
+            .into_iter()
+            .filter(|days| {
+                let start = start.shifted(*days).instant();
+                !self
+                    .excluded
+                    .iter()
+                    .any(|excluded| excluded.instant() == start)
+            })
+            .filter(|days| end.shifted(*days).instant() > from)
+            .map(|days| Event {
+                start: start.shifted(days).resolve(),
+                end: end.shifted(days).resolve(),
+            })
+            .collect()
+    }
+}
+
+/// A date or time as written in the calendar, along with the timezone it is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Time {
+    time: NaiveDateTime,
+    zone: Zone,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Zone {
+    Utc,
+    Tz(Tz),
+    /// No timezone, so it is in the timezone of the schedule
+    Floating,
+}
+
+impl Time {
+    /// Parses a date or time like `20241224`, `20241224T100000Z` or `20241224T100000` with a `TZID` parameter
+    fn parse(params: &str, value: &str) -> Option<Self> {
+        if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+            return Some(Time {
+                time: date.and_time(Default::default()),
+                zone: Zone::Floating,
+            });
+        }
+        if let Some(utc) = value.strip_suffix('Z') {
+            let time = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+            return Some(Time {
+                time,
+                zone: Zone::Utc,
+            });
+        }
+
+        let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+        let tz = params
+            .split(';')
+            .find_map(|param| param.strip_prefix("TZID="))
+            .and_then(|tz| tz.trim_matches('"').parse::<Tz>().ok());
+        Some(Time {
+            time,
+            zone: tz.map_or(Zone::Floating, Zone::Tz),
+        })
+    }
+
+    /// The same time of day `days` later, so that events in a timezone keep their time across DST transitions
+    fn shifted(self, days: i64) -> Self {
+        Time {
+            time: self.time + TimeDelta::days(days),
+            ..self
+        }
+    }
+
+    fn resolve(self) -> EventTime {
+        match self.zone {
+            Zone::Utc => EventTime::Utc(self.time.and_utc()),
+            Zone::Tz(tz) => match tz.from_local_datetime(&self.time).earliest() {
+                Some(time) => EventTime::Utc(time.to_utc()),
+                None => EventTime::Local(self.time),
+            },
+            Zone::Floating => EventTime::Local(self.time),
+        }
+    }
+
+    /// The time in UTC, or as written if it has no timezone, for comparing times from the same calendar
+    fn instant(self) -> NaiveDateTime {
+        match self.resolve() {
+            EventTime::Utc(time) => time.naive_utc(),
+            EventTime::Local(time) => time,
+        }
+    }
+}
+
+/// A daily or weekly `RRULE`, e.g. `FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE;UNTIL=20261231T000000Z`,
+/// which may also have a `COUNT`. Weeks start on Monday.
+#[derive(Debug)]
+struct Rule {
+    weekly: bool,
+    interval: i64,
+    count: Option<usize>,
+    until: Option<Time>,
+    /// Days of the week the event takes place on, which for weekly events defaults to the day it starts on
+    weekdays: Vec<Weekday>,
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Self, String> {
+        let mut weekly = None;
+        let mut interval = 1;
+        let mut count = None;
+        let mut until = None;
+        let mut weekdays = Vec::new();
+        for part in rule.split(';').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("invalid RRULE part {part:?}"))?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    weekly = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => false,
+                        "WEEKLY" => true,
+                        _ => return Err(format!("FREQ={value} is not supported")),
+                    })
+                }
+                "INTERVAL" => {
+                    interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                        .ok_or_else(|| format!("invalid INTERVAL {value:?}"))?
+                }
+                "COUNT" => {
+                    count = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid COUNT {value:?}"))?,
+                    )
+                }
+                "UNTIL" => {
+                    until = Some(
+                        Time::parse("", value).ok_or_else(|| format!("invalid UNTIL {value:?}"))?,
+                    )
+                }
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        weekdays.push(
+                            parse_weekday(day)
+                                .ok_or_else(|| format!("BYDAY={value} is not supported"))?,
+                        );
+                    }
+                }
+                // Only matters for weekly events with an interval that are on several days, which is rare
+                "WKST" => {}
+                _ => return Err(format!("{key} is not supported")),
+            }
+        }
+
+        Ok(Rule {
+            weekly: weekly.ok_or("FREQ is missing")?,
+            interval,
+            count,
+            until,
+            weekdays,
+        })
+    }
+
+    /// Returns how many days after `start` each occurrence starts,
+    /// ending with the last one before `horizon`, `UNTIL` or `COUNT`. The first occurrence is always `start`.
+    fn day_offsets(&self, start: Time, horizon: NaiveDateTime) -> Vec<i64> {
+        let first = start.time.date();
+        let first_week = first.week(Weekday::Mon).first_day();
+        let weekdays = match self.weekdays.as_slice() {
+            [] if self.weekly => vec![first.weekday()],
+            weekdays => weekdays.to_vec(),
+        };
+
+        let mut offsets = vec![0];
+        for days in 1.. {
+            let occurrence = start.shifted(days);
+            if occurrence.instant() > horizon
+                || self
+                    .until
+                    .is_some_and(|until| occurrence.instant() > until.instant())
+                || self.count.is_some_and(|count| offsets.len() >= count)
+            {
+                break;
+            }
+
+            let date = occurrence.time.date();
+            let on_weekday = weekdays.is_empty() || weekdays.contains(&date.weekday());
+            let period = if self.weekly {
+                (date.week(Weekday::Mon).first_day() - first_week).num_days() / 7
+            } else {
+                days
+            };
+            if on_weekday && period % self.interval == 0 {
+                offsets.push(days);
+            }
+        }
+        offsets
+    }
+}
+
+/// Parses a day of the week as written in `BYDAY`, e.g. `MO`
+fn parse_weekday(day: &str) -> Option<Weekday> {
+    Some(match day.trim().to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        "2026-10-16T00:00:00Z".parse().unwrap()
+    }
+
+    /// Parses a calendar with a single event with the given properties
+    fn parse_event(properties: &str) -> Vec<Event> {
+        parse(
+            &format!("BEGIN:VCALENDAR\nBEGIN:VEVENT\n{properties}\nEND:VEVENT\nEND:VCALENDAR\n"),
+            now(),
+        )
+    }
+
+    fn utc(time: &str) -> EventTime {
+        EventTime::Utc(time.parse().unwrap())
+    }
+
+    fn starts(events: &[Event]) -> Vec<EventTime> {
+        events.iter().map(|event| event.start).collect()
+    }
+
+    #[test]
+    fn weekly_events_keep_their_local_time_across_dst() {
+        let events = parse_event(
+            "DTSTART;TZID=Europe/Stockholm:20261012T100000\n\
+             DTEND;TZID=Europe/Stockholm:20261012T110000\n\
+             RRULE:FREQ=WEEKLY;COUNT=3",
+        );
+        assert_eq!(
+            events,
+            [
+                Event {
+                    start: utc("2026-10-12T08:00:00Z"),
+                    end: utc("2026-10-12T09:00:00Z"),
+                },
+                Event {
+                    start: utc("2026-10-19T08:00:00Z"),
+                    end: utc("2026-10-19T09:00:00Z"),
+                },
+                // The clock is turned back on 2026-10-25
+                Event {
+                    start: utc("2026-10-26T09:00:00Z"),
+                    end: utc("2026-10-26T10:00:00Z"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn daily_events_stop_at_until_and_skip_exdates() {
+        let events = parse_event(
+            "DTSTART:20261012T090000Z\n\
+             DTEND:20261012T093000Z\n\
+             RRULE:FREQ=DAILY;UNTIL=20261015T090000Z\n\
+             EXDATE:20261013T090000Z",
+        );
+        assert_eq!(
+            starts(&events),
+            [
+                utc("2026-10-12T09:00:00Z"),
+                utc("2026-10-14T09:00:00Z"),
+                utc("2026-10-15T09:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn weekly_events_on_several_days_with_an_interval() {
+        let events = parse_event(
+            "DTSTART:20261012T090000Z\n\
+             DTEND:20261012T100000Z\n\
+             RRULE:FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE;COUNT=4",
+        );
+        assert_eq!(
+            starts(&events),
+            [
+                utc("2026-10-12T09:00:00Z"),
+                utc("2026-10-14T09:00:00Z"),
+                utc("2026-10-26T09:00:00Z"),
+                utc("2026-10-28T09:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn endless_events_are_repeated_as_far_as_the_schedule_searches() {
+        // Started long ago, so only the occurrences within the search are kept
+        let events = parse_event(
+            "DTSTART:20200106T090000Z\n\
+             DTEND:20200106T100000Z\n\
+             RRULE:FREQ=WEEKLY",
+        );
+        let search = TimeDelta::days(crate::MAX_SEARCH_DAYS as i64);
+        assert!(events.iter().all(|event| match (event.start, event.end) {
+            (EventTime::Utc(start), EventTime::Utc(end)) =>
+                end > now() - search && start <= now() + search,
+            _ => false,
+        }));
+        // Two years of Mondays
+        assert_eq!(events.len(), 104);
+    }
+
+    #[test]
+    fn all_day_events_repeat_on_whole_days() {
+        let events = parse_event("DTSTART;VALUE=DATE:20261012\nRRULE:FREQ=DAILY;COUNT=2");
+        let local = |time: &str| EventTime::Local(time.parse().unwrap());
+        assert_eq!(
+            events,
+            [
+                Event {
+                    start: local("2026-10-12T00:00:00"),
+                    end: local("2026-10-13T00:00:00"),
+                },
+                Event {
+                    start: local("2026-10-13T00:00:00"),
+                    end: local("2026-10-14T00:00:00"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_rules_only_count_the_first_occurrence() {
+        let events = parse_event(
+            "DTSTART:20261012T090000Z\n\
+             DTEND:20261012T100000Z\n\
+             RRULE:FREQ=MONTHLY;COUNT=3",
+        );
+        assert_eq!(starts(&events), [utc("2026-10-12T09:00:00Z")]);
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+
+/// Source of the current time, so that time can be controlled instead of always following the system clock.
+/// Clocks are `Send` and `Sync` so that a [`crate::Context`] can be moved to another task.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// A shared clock, e.g. a [`ManualClock`] that is moved while a [`crate::Context`] is using it
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> DateTime<Utc> {
+        (**self).now()
+    }
+}
+
+/// The system clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until it is explicitly moved
+pub struct ManualClock {
+    time: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(time: DateTime<Utc>) -> Self {
+        Self {
+            time: Mutex::new(time),
+        }
+    }
+
+    pub fn set(&self, time: DateTime<Utc>) {
+        *self.time.lock().unwrap() = time;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.time.lock().unwrap()
+    }
+}
@@ -0,0 +1,169 @@
+use chrono::{Datelike, NaiveDate, NaiveTime};
+use serde::Deserialize;
+
+/// A standard five field cron expression of minute, hour, day of month, month and day of week, e.g. `"0 9-17 * * MON-FRI"`.
+/// Every minute it matches is valid time in the schedule, so `"* 9-16 * * *"` is the same as a window from 9:00 to 16:59.
+///
+/// Fields accept `*`, numbers, ranges like `9-17`, steps like `*/15` or `0-30/10`, and lists of these like `0,30`.
+/// Months and days of week also accept their three letter names, and Sunday is either 0 or 7.
+/// Like in cron, if both the day of month and day of week are restricted, a date matching either of them is enough.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct Cron {
+    /// Bit `n` is set if minute `n` matches, and likewise for the other fields
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Bit `n` is set if the day `n` days from Sunday matches
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "cron expression {expression:?} must have 5 fields, minute, hour, day of month, month and day of week"
+            ));
+        };
+
+        let mut weekdays_bits = parse_field(weekdays, 0, 7, &WEEKDAYS)?;
+        // Both 0 and 7 are Sunday
+        if weekdays_bits & (1 << 7) != 0 {
+            weekdays_bits |= 1;
+        }
+        Ok(Cron {
+            minutes: parse_field(minutes, 0, 59, &[])?,
+            hours: parse_field(hours, 0, 23, &[])?,
+            days: parse_field(days, 1, 31, &[])?,
+            months: parse_field(months, 1, 12, &MONTHS)?,
+            weekdays: weekdays_bits,
+            // As in cron, only a field starting with `*` counts as unrestricted, even with a step
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+}
+
+/// Parses a field into a set of values between `min` and `max`, as bits.
+/// `names` are alternatives to the values, starting from `min`.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step {step:?} in cron field {field:?}"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_value(start, min, max, names)?,
+                parse_value(end, min, max, names)?,
+            )
+        } else {
+            let value = parse_value(range, min, max, names)?;
+            // A single value with a step, e.g. `5/15`, starts at the value and continues to the end
+            if step > 1 {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+        if start > end {
+            return Err(format!(
+                "range {range:?} in cron field {field:?} is backwards"
+            ));
+        }
+        for value in (start..=end).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn parse_value(value: &str, min: u32, max: u32, names: &[&str]) -> Result<u32, String> {
+    if let Some(i) = names
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(value))
+    {
+        return Ok(i as u32 + min);
+    }
+    value
+        .parse()
+        .ok()
+        .filter(|value| (min..=max).contains(value))
+        .ok_or_else(|| format!("invalid cron value {value:?}, expected {min} to {max}"))
+}
+
+impl Cron {
+    /// Whether the expression matches any time on the given date
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// Returns the start and end of each run of matching minutes on the given date, including the whole last minute
+    pub fn windows(&self, date: NaiveDate) -> Vec<(NaiveTime, NaiveTime)> {
+        let mut windows = vec![];
+        if !self.matches_date(date) {
+            return windows;
+        }
+
+        let mut start = None;
+        for minute_of_day in 0..24 * 60 {
+            let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+            let matches = self.hours & (1 << hour) != 0 && self.minutes & (1 << minute) != 0;
+            match (matches, start) {
+                (true, None) => start = Some((hour, minute)),
+                (false, Some(_)) => {
+                    windows.push(window(start.take().unwrap(), minute_of_day - 1));
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = start {
+            windows.push(window(start, 24 * 60 - 1));
+        }
+        windows
+    }
+}
+
+/// A window from the start of the minute `start` to the end of the minute `last_minute_of_day`
+fn window((hour, minute): (u32, u32), last_minute_of_day: u32) -> (NaiveTime, NaiveTime) {
+    (
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap(),
+        NaiveTime::from_hms_nano_opt(
+            last_minute_of_day / 60,
+            last_minute_of_day % 60,
+            59,
+            999_999_999,
+        )
+        .unwrap(),
+    )
+}
@@ -0,0 +1,145 @@
+//! D-Bus service for desktop tooling and `busctl`. This is only available with the `dbus` feature.
+
+use std::path::Path;
+
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+#[cfg(feature = "dbus")]
+use {
+    tokio::sync::oneshot,
+    tracing::{info, warn},
+    zbus::{fdo, object_server::SignalEmitter, Connection},
+};
+
+use crate::Command;
+
+/// Name the service is owned under on the bus
+#[cfg(feature = "dbus")]
+const NAME: &str = "org.elekrisk.RandomSpeaker";
+/// Path of the object implementing the interface
+#[cfg(feature = "dbus")]
+const PATH: &str = "/org/elekrisk/RandomSpeaker";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DbusConfig {
+    /// Which bus to register the service on
+    #[serde(default)]
+    pub bus: Bus,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Bus {
+    /// The bus of the logged in user
+    #[default]
+    Session,
+    /// The bus shared by the whole system, which needs a policy allowing us to own the name
+    System,
+}
+
+/// Handle for emitting signals from the main loop
+#[derive(Clone)]
+pub struct Publisher {
+    #[cfg(feature = "dbus")]
+    connection: Connection,
+}
+
+impl Publisher {
+    /// Emits `SoundPlayed` for a sound that was played, without waiting for it to be sent
+    #[cfg_attr(not(feature = "dbus"), allow(unused_variables))]
+    pub fn played(&self, path: &Path, time: DateTime<FixedOffset>) {
+        #[cfg(feature = "dbus")]
+        {
+            let connection = self.connection.clone();
+            let path = path.display().to_string();
+            tokio::spawn(async move {
+                let result = match SignalEmitter::new(&connection, PATH) {
+                    Ok(emitter) => Control::sound_played(&emitter, &path, &time.to_rfc3339()).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warn!("Could not emit SoundPlayed on D-Bus: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// Registers the service on the bus, returning a publisher for the main loop.
+/// Method calls are forwarded to the main loop as commands.
+#[cfg_attr(not(feature = "dbus"), allow(unused_variables))]
+pub async fn start(config: DbusConfig, commands: Sender<Command>) -> anyhow::Result<Publisher> {
+    #[cfg(not(feature = "dbus"))]
+    anyhow::bail!(
+        "D-Bus is configured on the {:?} bus, but this build does not include the dbus feature",
+        config.bus
+    );
+
+    #[cfg(feature = "dbus")]
+    {
+        let builder = match config.bus {
+            Bus::Session => zbus::connection::Builder::session()?,
+            Bus::System => zbus::connection::Builder::system()?,
+        };
+        let connection = builder
+            .name(NAME)?
+            .serve_at(PATH, Control { commands })?
+            .build()
+            .await?;
+        info!("Registered {NAME} on the {:?} bus", config.bus);
+        Ok(Publisher { connection })
+    }
+}
+
+#[cfg(feature = "dbus")]
+struct Control {
+    commands: Sender<Command>,
+}
+
+#[cfg(feature = "dbus")]
+#[zbus::interface(name = "org.elekrisk.RandomSpeaker")]
+impl Control {
+    /// Plays a sound right away, without affecting the schedule
+    async fn play_now(&self) -> fdo::Result<()> {
+        self.send(Command::PlayNow).await
+    }
+
+    /// Throws away the scheduled play and schedules a new one
+    async fn skip(&self) -> fdo::Result<()> {
+        self.send(Command::Reschedule).await
+    }
+
+    /// Returns the time of the next scheduled play in RFC 3339 format, or an empty string if there is none
+    async fn get_next_play(&self) -> fdo::Result<String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(Command::Status(reply_tx)).await?;
+        let status = reply_rx.await.map_err(|_| stopped())?;
+        Ok(status
+            .next_play
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_default())
+    }
+
+    /// Silences sounds without pausing the schedule, or brings them back at full volume
+    async fn set_muted(&self, muted: bool) -> fdo::Result<()> {
+        self.send(Command::Volume(if muted { 0.0 } else { 1.0 }))
+            .await
+    }
+
+    /// Emitted with the path of each sound that is played, and when
+    #[zbus(signal)]
+    async fn sound_played(emitter: &SignalEmitter<'_>, path: &str, time: &str) -> zbus::Result<()>;
+}
+
+#[cfg(feature = "dbus")]
+impl Control {
+    async fn send(&self, command: Command) -> fdo::Result<()> {
+        self.commands.send(command).await.map_err(|_| stopped())
+    }
+}
+
+#[cfg(feature = "dbus")]
+fn stopped() -> fdo::Error {
+    fdo::Error::Failed("the main loop has stopped".to_owned())
+}
@@ -0,0 +1,82 @@
+//! How the time between plays is distributed between `lower_bound` and `upper_bound`
+
+use rand::Rng;
+use rand_distr::{Distribution as _, Exp, Normal};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Distribution {
+    #[default]
+    Uniform,
+    Normal,
+    /// Plays come at a constant rate after `lower_bound`, like a Poisson process,
+    /// so most gaps are short with the occasional long one
+    Exponential,
+}
+
+/// A distribution with its parameters checked, which picks numbers of seconds between the bounds
+#[derive(Debug, Clone, Copy)]
+pub struct Sampler {
+    lower: f32,
+    upper: f32,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Uniform,
+    Normal(Normal<f32>),
+    /// Counted from the lower bound
+    Exponential(Exp<f32>),
+}
+
+impl Sampler {
+    /// Creates a sampler for `distribution` between `lower` and `upper`, which must be less than `upper`.
+    /// `mean` defaults to halfway between the bounds, and the `std_dev` of the normal distribution
+    /// to a quarter of the distance between them.
+    pub fn new(
+        distribution: Distribution,
+        lower: f32,
+        upper: f32,
+        mean: Option<f32>,
+        std_dev: Option<f32>,
+    ) -> Result<Self, String> {
+        let mean = mean.unwrap_or((lower + upper) / 2.0);
+        if !mean.is_finite() {
+            return Err(format!("mean ({mean}) must be a finite number"));
+        }
+
+        let kind = match distribution {
+            Distribution::Uniform => Kind::Uniform,
+            Distribution::Normal => {
+                let std_dev = std_dev.unwrap_or((upper - lower) / 4.0);
+                if !(std_dev.is_finite() && std_dev >= 0.0) {
+                    return Err(format!(
+                        "std_dev ({std_dev}) of the normal distribution must not be negative"
+                    ));
+                }
+                Kind::Normal(Normal::new(mean, std_dev).map_err(|e| e.to_string())?)
+            }
+            Distribution::Exponential => {
+                // Starts counting at the lower bound, so the mean is reached by waiting the rest of the way on average
+                if mean <= lower {
+                    return Err(format!(
+                        "mean ({mean}) of the exponential distribution must be greater than lower_bound ({lower})"
+                    ));
+                }
+                Kind::Exponential(Exp::new(1.0 / (mean - lower)).map_err(|e| e.to_string())?)
+            }
+        };
+        Ok(Sampler { lower, upper, kind })
+    }
+
+    /// Picks a number of seconds between the bounds
+    pub fn sample(&self, rng: &mut (impl Rng + ?Sized)) -> f32 {
+        match self.kind {
+            Kind::Uniform => rng.gen_range(self.lower..self.upper),
+            Kind::Normal(normal) => normal.sample(rng).clamp(self.lower, self.upper),
+            Kind::Exponential(exp) => (self.lower + exp.sample(rng)).min(self.upper),
+        }
+    }
+}
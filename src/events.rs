@@ -0,0 +1,35 @@
+use std::path::Path;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Serialize;
+
+use crate::PlayReason;
+
+/// Transitions of the schedule, printed as JSON lines with `--json` so that scripts can follow along
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A new play was scheduled
+    Scheduled { next_play: DateTime<FixedOffset> },
+    /// Waiting for a play that was scheduled earlier
+    Waiting { next_play: DateTime<FixedOffset> },
+    /// A play was skipped instead of played
+    Skipped { reason: &'a str },
+    /// A sound was played, or would have been in a dry run
+    Played { file: &'a Path, reason: PlayReason },
+}
+
+/// Prints the event as a single line of JSON to stdout
+pub fn print(timestamp: DateTime<Utc>, event: &Event) {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        timestamp: DateTime<Utc>,
+        #[serde(flatten)]
+        event: &'a Event<'a>,
+    }
+
+    match serde_json::to_string(&Line { timestamp, event }) {
+        Ok(line) => println!("{line}"),
+        Err(e) => tracing::warn!("Could not serialize event: {e}"),
+    }
+}
@@ -0,0 +1,105 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::Html,
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::Sender, oneshot};
+use tracing::{error, info};
+
+use crate::{status::Status, Command};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpConfig {
+    /// Address to listen on, e.g. `"0.0.0.0:8080"`
+    pub bind: SocketAddr,
+}
+
+/// A page showing the status, with buttons for the most common commands
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+#[derive(Debug, Deserialize)]
+struct Volume {
+    volume: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct NextPlay {
+    next_play: Option<DateTime<FixedOffset>>,
+    seconds_remaining: Option<i64>,
+}
+
+/// Serves the HTTP API, forwarding requests to the main loop as commands
+pub async fn serve(config: HttpConfig, commands: Sender<Command>) {
+    let app = Router::new()
+        .route("/", get(|| async { Html(DASHBOARD) }))
+        .route("/status", get(status))
+        .route("/next-play", get(next_play))
+        .route("/play", post(|commands| send(commands, Command::PlayNow)))
+        .route(
+            "/reschedule",
+            post(|commands| send(commands, Command::Reschedule)),
+        )
+        .route(
+            "/skip",
+            post(|commands| send(commands, Command::Reschedule)),
+        )
+        .route("/volume", put(volume))
+        .route("/reload", post(|commands| send(commands, Command::Reload)))
+        .route("/pause", post(|commands| send(commands, Command::Pause)))
+        .route("/resume", post(|commands| send(commands, Command::Resume)))
+        .with_state(commands);
+
+    let listener = match tokio::net::TcpListener::bind(config.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not bind HTTP server to {}: {e}", config.bind);
+            return;
+        }
+    };
+
+    info!("HTTP server listening on {}", config.bind);
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("HTTP server error: {e}");
+    }
+}
+
+async fn send(State(commands): State<Sender<Command>>, command: Command) -> StatusCode {
+    match commands.send(command).await {
+        Ok(()) => StatusCode::OK,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// Sets the volume factor from a body like `{"volume": 0.5}`
+async fn volume(commands: State<Sender<Command>>, Json(body): Json<Volume>) -> StatusCode {
+    if !body.volume.is_finite() || body.volume < 0.0 {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    send(commands, Command::Volume(body.volume)).await
+}
+
+async fn status(State(commands): State<Sender<Command>>) -> Result<Json<Status>, StatusCode> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    commands
+        .send(Command::Status(reply_tx))
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    reply_rx
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)
+}
+
+async fn next_play(commands: State<Sender<Command>>) -> Result<Json<NextPlay>, StatusCode> {
+    let Json(status) = status(commands).await?;
+    Ok(Json(NextPlay {
+        next_play: status.next_play,
+        seconds_remaining: status.seconds_remaining,
+    }))
+}
@@ -0,0 +1,5037 @@
+//! Plays random sounds at random times, within the times a schedule allows.
+//! The `speakthing` binary only parses the command line, runs a [`Context`] and turns stdin and signals into [`Command`]s,
+//! so the scheduler can be embedded in other applications the same way, without taking over the process.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{
+    DateTime, Datelike, FixedOffset, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime,
+    TimeDelta, TimeZone, Timelike, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rodio::{
+    cpal::traits::{DeviceTrait, HostTrait},
+    source::SineWave,
+    Decoder, OutputStream, OutputStreamHandle, Sink, Source,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+    time::{Instant, Sleep},
+};
+use tracing::{debug, error, info, warn};
+
+use calendar::CalendarMode;
+use clock::{Clock, ManualClock};
+use cron::Cron;
+use distribution::{Distribution, Sampler};
+use events::Event;
+use rng::SpeakerRng;
+
+mod calendar;
+pub mod clock;
+mod cron;
+mod dbus;
+mod distribution;
+mod events;
+mod http;
+mod metrics;
+mod mqtt;
+mod rng;
+pub mod status;
+pub mod stdin;
+mod systemd;
+mod webhook;
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    /// Path to the config file
+    #[arg(long, default_value = "config.toml")]
+    pub config: PathBuf,
+    /// Directory to pick sounds from
+    #[arg(long, default_value = "sounds")]
+    pub sounds_dir: PathBuf,
+    /// Directory the state files are kept in, when they are given as relative paths.
+    /// Defaults to `$STATE_DIRECTORY` as set by systemd's `StateDirectory=`, or else `$XDG_STATE_HOME/random-speaker`,
+    /// which is `~/.local/state/random-speaker` unless set otherwise. It is created if missing.
+    #[arg(long)]
+    pub state_dir: Option<PathBuf>,
+    /// File the next scheduled play time is persisted to
+    #[arg(long, visible_alias = "state-file", default_value = "next-play")]
+    pub next_play: PathBuf,
+    /// File the time of the last actual play is persisted to
+    #[arg(long, default_value = "last-play")]
+    pub last_play: PathBuf,
+    /// File the number of plays during the current day is persisted to
+    #[arg(long, default_value = "play-count")]
+    pub play_count: PathBuf,
+    /// File the time each sound was last played is persisted to
+    #[arg(long, default_value = "play-times")]
+    pub play_times: PathBuf,
+    /// File the state of the seeded random number generator is persisted to
+    #[arg(long, default_value = "rng-state")]
+    pub rng_state: PathBuf,
+    /// Run the schedule without opening an audio device, only logging what would be played
+    #[arg(long)]
+    pub dry_run: bool,
+    /// How long to wait for file changes to settle before reloading, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    pub debounce_ms: u64,
+    /// Print scheduled and played sounds to stdout as lines of JSON, and logs to stderr
+    #[arg(long)]
+    pub json: bool,
+    /// Play a sound at startup to check that audio output works, either the given file or a beep
+    #[arg(long, value_name = "FILE", num_args = 0..=1)]
+    pub test_sound: Option<Option<PathBuf>>,
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+impl Args {
+    /// The directory relative state files are kept in, see `state_dir`.
+    /// Returns `None` if not even `$HOME` is set, in which case they are kept in the working directory.
+    fn state_dir(&self) -> Option<PathBuf> {
+        if let Some(dir) = &self.state_dir {
+            return Some(dir.clone());
+        }
+        // systemd separates several directories with colons, and the first one is ours
+        if let Some(dir) =
+            std::env::var_os("STATE_DIRECTORY").and_then(|dirs| std::env::split_paths(&dirs).next())
+        {
+            return Some(dir);
+        }
+        // Relative paths are to be ignored according to the XDG Base Directory Specification
+        let state_home = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state"))
+            })?;
+        Some(state_home.join("random-speaker"))
+    }
+
+    /// The options for a [`Context`] given on the command line, with the state files resolved against the state directory,
+    /// which is created if missing
+    pub fn options(&self) -> Options {
+        let state_dir = self.state_dir();
+        if let Some(dir) = &state_dir {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                warn!("Could not create state directory {}: {e}", dir.display());
+            }
+        }
+        let state_path = |path: &Path| match &state_dir {
+            Some(dir) => dir.join(path),
+            None => path.to_path_buf(),
+        };
+
+        Options {
+            config: self.config.clone(),
+            sounds_dir: self.sounds_dir.clone(),
+            next_play: state_path(&self.next_play),
+            last_play: state_path(&self.last_play),
+            play_count: state_path(&self.play_count),
+            play_times: state_path(&self.play_times),
+            rng_state: state_path(&self.rng_state),
+            debounce: Duration::from_millis(self.debounce_ms),
+            json: self.json,
+        }
+    }
+}
+
+/// What a [`Context`] needs to know besides its config, which is read from `config`
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Path to the config file, which is reloaded whenever it changes
+    pub config: PathBuf,
+    /// Directory to pick sounds from, unless the config says otherwise
+    pub sounds_dir: PathBuf,
+    /// File the next scheduled play time is persisted to
+    pub next_play: PathBuf,
+    /// File the time of the last actual play is persisted to
+    pub last_play: PathBuf,
+    /// File the number of plays during the current day is persisted to
+    pub play_count: PathBuf,
+    /// File the time each sound was last played is persisted to
+    pub play_times: PathBuf,
+    /// File the state of the seeded random number generator is persisted to
+    pub rng_state: PathBuf,
+    /// How long to wait for file changes to settle before reloading
+    pub debounce: Duration,
+    /// Print scheduled and played sounds to stdout as lines of JSON
+    pub json: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            config: PathBuf::from("config.toml"),
+            sounds_dir: PathBuf::from("sounds"),
+            next_play: PathBuf::from("next-play"),
+            last_play: PathBuf::from("last-play"),
+            play_count: PathBuf::from("play-count"),
+            play_times: PathBuf::from("play-times"),
+            rng_state: PathBuf::from("rng-state"),
+            debounce: Duration::from_secs(1),
+            json: false,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    /// Print upcoming play times according to the config, without playing anything
+    Simulate {
+        /// Time to start simulating from, defaults to now
+        #[arg(long)]
+        from: Option<NaiveDateTime>,
+        /// Number of play times to print
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+        /// Seed for the random number generator, to get the same play times every run
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Control the instance that is already running, through the socket configured under `[status]`
+    Ctl {
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+}
+
+#[derive(Debug, Subcommand, Clone, Copy)]
+pub enum CtlAction {
+    /// Play a sound right away, without affecting the schedule
+    PlayNow,
+    /// Throw away the scheduled play and schedule a new one
+    Skip,
+    /// Print the next play time and the state of the schedule
+    Status,
+    /// Same as skip
+    Reschedule,
+}
+
+/// Whether `path` is `base` or inside it.
+/// The file watcher may report absolute paths even if `base` is relative, and the file may already be gone if it was replaced,
+/// so the paths are compared both as given, made absolute and with symlinks resolved.
+fn is_within(path: &Path, base: &Path) -> bool {
+    if path.starts_with(base) {
+        return true;
+    }
+    if let (Ok(path), Ok(base)) = (std::path::absolute(path), std::path::absolute(base)) {
+        if path.starts_with(base) {
+            return true;
+        }
+    }
+    match (path.canonicalize(), base.canonicalize()) {
+        (Ok(path), Ok(base)) => path.starts_with(base),
+        _ => false,
+    }
+}
+
+/// Opens the output device with the given name, or the default device if `None`.
+/// Falls back to the default device if there is no device with the name.
+pub fn open_output(name: Option<&str>) -> anyhow::Result<(OutputStream, OutputStreamHandle)> {
+    let Some(name) = name else {
+        return Ok(OutputStream::try_default()?);
+    };
+
+    let devices: Vec<_> = rodio::cpal::default_host().output_devices()?.collect();
+    if let Some(device) = devices
+        .iter()
+        .find(|device| device.name().is_ok_and(|device| device == name))
+    {
+        info!("Using output device {name}");
+        return Ok(OutputStream::try_from_device(device)?);
+    }
+
+    let names: Vec<_> = devices
+        .iter()
+        .filter_map(|device| device.name().ok())
+        .collect();
+    warn!(
+        "Could not find output device {name}, using the default device. Available devices: {}",
+        names.join(", ")
+    );
+    Ok(OutputStream::try_default()?)
+}
+
+/// Queues a sound on the sink right away, so that it is immediately clear whether the audio output works
+pub fn play_test_sound(sink: &Sink, path: Option<&Path>) {
+    match path {
+        Some(path) => match open_sound(path) {
+            Ok(decoder) => {
+                info!("Playing test sound {}", path.display());
+                sink.append(decoder);
+            }
+            Err(e) => error!("Could not play test sound {}: {e}", path.display()),
+        },
+        None => {
+            info!("Playing test beep");
+            sink.append(
+                SineWave::new(440.0)
+                    .take_duration(Duration::from_millis(500))
+                    .amplify(0.2),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct BaseConfig {
+    /// Used on dates no profile applies to
+    general: General,
+    schedule: Schedule,
+    /// Alternative settings, each applying on the dates its schedule has any windows on.
+    /// If several apply, the first one is used.
+    #[serde(default)]
+    profiles: Vec<Profile>,
+    #[serde(alias = "api")]
+    http: Option<http::HttpConfig>,
+    metrics: Option<metrics::MetricsConfig>,
+    mqtt: Option<mqtt::MqttConfig>,
+    webhook: Option<webhook::WebhookConfig>,
+    status: Option<status::StatusConfig>,
+    calendar: Option<calendar::CalendarConfig>,
+    dbus: Option<dbus::DbusConfig>,
+    burst: Option<BurstConfig>,
+}
+
+/// Occasionally plays several sounds back to back instead of just one
+#[derive(Debug, Deserialize, Clone)]
+struct BurstConfig {
+    /// Chance of each play being a burst, from 0 to 1
+    probability: f64,
+    /// Fewest sounds played in a burst
+    #[serde(default = "default_burst_min_count")]
+    min_count: usize,
+    /// Most sounds played in a burst
+    #[serde(default = "default_burst_max_count")]
+    max_count: usize,
+}
+
+const fn default_burst_min_count() -> usize {
+    2
+}
+
+const fn default_burst_max_count() -> usize {
+    4
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Profile {
+    name: String,
+    general: General,
+    schedule: Schedule,
+}
+
+impl BaseConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses and validates the contents of a config file
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let config: BaseConfig = toml::from_str(contents)?;
+        config.validate().map_err(anyhow::Error::msg)?;
+        Ok(config)
+    }
+
+    /// Path of the status socket, if it is served
+    pub fn status_socket(&self) -> Option<anyhow::Result<PathBuf>> {
+        self.status.as_ref().map(status::StatusConfig::path)
+    }
+
+    /// Name of the audio device to play on, if not the default one
+    pub fn output_device(&self) -> Option<&str> {
+        self.general.output_device.as_deref()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_settings(&self.general, &self.schedule)?;
+
+        if let Some(burst) = &self.burst {
+            if !(0.0..=1.0).contains(&burst.probability) {
+                return Err(format!(
+                    "burst probability ({}) must be between 0 and 1",
+                    burst.probability
+                ));
+            }
+            if burst.min_count == 0 || burst.min_count > burst.max_count {
+                return Err(format!(
+                    "burst min_count ({}) must be at least 1 and at most max_count ({})",
+                    burst.min_count, burst.max_count
+                ));
+            }
+        }
+
+        // With profiles, the top-level schedule may be left empty so that only the profiles are used
+        if self.profiles.is_empty() && self.schedule.is_empty() {
+            return Err("no weekdays are scheduled".into());
+        }
+
+        for profile in &self.profiles {
+            validate_settings(&profile.general, &profile.schedule)
+                .map_err(|e| format!("profile {}: {e}", profile.name))?;
+            if profile.schedule.is_empty() {
+                return Err(format!(
+                    "profile {}: no weekdays are scheduled",
+                    profile.name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the config to use on the given date, with the settings of the first profile that applies to it,
+    /// along with the name of that profile
+    fn for_date(&self, date: NaiveDate) -> (BaseConfig, Option<String>) {
+        let Some(profile) = self
+            .profiles
+            .iter()
+            .find(|profile| profile.schedule.is_scheduled(date))
+        else {
+            return (self.clone(), None);
+        };
+
+        let config = BaseConfig {
+            general: profile.general.clone(),
+            schedule: profile.schedule.clone(),
+            profiles: self.profiles.clone(),
+            http: self.http.clone(),
+            metrics: self.metrics.clone(),
+            mqtt: self.mqtt.clone(),
+            webhook: self.webhook.clone(),
+            status: self.status.clone(),
+            calendar: self.calendar.clone(),
+            dbus: self.dbus.clone(),
+            burst: self.burst.clone(),
+        };
+        (config, Some(profile.name.clone()))
+    }
+}
+
+fn validate_settings(general: &General, schedule: &Schedule) -> Result<(), String> {
+    for pattern in general.exclude.iter().chain(&general.include) {
+        glob::Pattern::new(pattern).map_err(|e| format!("invalid pattern {pattern:?}: {e}"))?;
+    }
+
+    if let Some(dir) = general.sound_dirs.iter().find(|dir| dir.weight() < 0.0) {
+        return Err(format!(
+            "weight of sound directory {} must not be negative",
+            dir.path().display()
+        ));
+    }
+
+    if general.mode == ScheduleMode::Timetable {
+        if !schedule.has_fixed_times() {
+            return Err("timetable mode requires `fixed_times` in the schedule".into());
+        }
+    } else if general.lower_bound >= general.upper_bound {
+        return Err(format!(
+            "lower_bound ({}) must be less than upper_bound ({})",
+            general.lower_bound, general.upper_bound
+        ));
+    } else {
+        general.sampler()?;
+    }
+
+    // Otherwise, plays that are within the bounds would be rescheduled before ever being reached
+    if let Some(max) = general.max_lookahead_seconds {
+        if (max as usize) < general.upper_bound {
+            return Err(format!(
+                "max_lookahead_seconds ({max}) must be at least upper_bound ({})",
+                general.upper_bound
+            ));
+        }
+    }
+
+    for window in schedule
+        .all_entries()
+        .flat_map(|entry| entry.days.iter().flatten())
+    {
+        // Windows going past midnight have already been split in two
+        if window.start_time >= window.end_time {
+            return Err(format!(
+                "start_time ({}) must be different from end_time ({})",
+                window.start_time, window.end_time
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+struct General {
+    /// Whether sounds play at random times or only at the `fixed_times` of the schedule
+    #[serde(default)]
+    mode: ScheduleMode,
+    /// Shortest time between plays, either in seconds or as a duration like `"10m"` or `"1h30m"`.
+    /// Not needed in timetable mode.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    lower_bound: usize,
+    /// Longest time between plays, in the same format as `lower_bound`
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    upper_bound: usize,
+    /// Timezone the schedule is in, e.g. `"Europe/Stockholm"`. Defaults to the system timezone.
+    timezone: Option<Tz>,
+    /// Directory to pick sounds from, overriding `--sounds-dir`
+    sounds_dir: Option<PathBuf>,
+    /// Several directories to pick sounds from, overriding `sounds_dir`.
+    /// Each directory is weighted as if they were all subdirectories of the same directory,
+    /// unless given a weight of its own like `{ path = "/mnt/sounds", weight = 2.0 }`.
+    #[serde(default)]
+    sound_dirs: Vec<SoundDir>,
+    /// Name of the audio device to play on. Defaults to the system default device.
+    /// Only read at startup, so changing it requires a restart.
+    output_device: Option<String>,
+    /// File to append a JSON line to for every sound played
+    history_file: Option<PathBuf>,
+    /// Glob patterns for names of files and directories in the sounds directory that should never be played,
+    /// e.g. `["README*", "*.tmp", "drafts"]`. Directory configs (`config.toml`) are always excluded.
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Glob patterns for names of files to play. If not empty, only files matching any of them are played.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Sounds longer than this are never played. Sounds whose duration can't be determined are always allowed.
+    max_duration_seconds: Option<f64>,
+    /// Files larger than this many bytes are never played
+    max_file_bytes: Option<u64>,
+    /// Seed for picking play times and sounds, to get the same sequence every time.
+    /// The position in the sequence is persisted, so a restart continues the sequence instead of starting it over.
+    seed: Option<u64>,
+    /// Minimum number of seconds between two plays, regardless of the bounds or the schedule.
+    /// Also accepted as `min_gap`, in the same format as `lower_bound`.
+    #[serde(default, alias = "min_gap", deserialize_with = "deserialize_seconds")]
+    min_gap_seconds: usize,
+    /// Avoid playing any of the last `no_repeat_history` sounds again
+    #[serde(default)]
+    no_repeat: bool,
+    #[serde(default = "default_no_repeat_history")]
+    no_repeat_history: usize,
+    /// Maximum number of sounds played per day, including at fixed times. Once reached, nothing more is played until the next valid day.
+    /// Plays in a window continuing past midnight count towards the day the window started on.
+    max_plays_per_day: Option<usize>,
+    /// What to do when it is time to play while the previous sound is still playing.
+    /// Has no effect with `allow_overlap`.
+    #[serde(default)]
+    overrun_policy: OverrunPolicy,
+    /// Master volume over the day, e.g. `[{ time = "7:00", volume = 0.3 }, { time = "12:00", volume = 1.0 }]`.
+    /// Applies on top of the volume of each file.
+    #[serde(default)]
+    volume_schedule: Vec<VolumePoint>,
+    /// Adjust the volume of each sound so that they all play at about the same loudness, before applying their volume
+    #[serde(default)]
+    normalize: bool,
+    /// Loudness sounds are normalized to, as the RMS of their samples from 0 to 1
+    #[serde(default = "default_normalize_target")]
+    normalize_target: f32,
+    /// Let a sound start while another is still playing, mixing them, instead of queueing it
+    #[serde(default)]
+    allow_overlap: bool,
+    /// Say the title of each sound, or its file name without extension, using text-to-speech before playing it
+    #[serde(default)]
+    announce: bool,
+    /// Text-to-speech command used by `announce`. It is given the text as its last argument
+    /// and should write a WAV file to stdout. If it fails, the sound is played without announcement.
+    #[serde(default = "default_announce_command")]
+    announce_command: Vec<String>,
+    /// Fade in each sound over this many milliseconds, can be overridden per file
+    #[serde(default)]
+    fade_in_ms: u64,
+    /// Fade out the end of each sound over this many milliseconds, can be overridden per file.
+    /// Only applies to sounds whose duration is known.
+    #[serde(default)]
+    fade_out_ms: u64,
+    /// When a sound is played while another is still playing, fade the playing one out over this many milliseconds
+    /// while the new one fades in, instead of following `overrun_policy`. Has no effect with `allow_overlap`.
+    #[serde(default)]
+    crossfade_ms: u64,
+    /// Play each sound at a random speed, and thereby pitch, up to this fraction faster or slower,
+    /// e.g. `0.1` for between 90% and 110%. At most 0.5. Files can opt out with `vary_speed = false`.
+    #[serde(default)]
+    speed_variation: f32,
+    /// How sounds are picked
+    #[serde(default)]
+    selection_mode: SelectionMode,
+    /// With the `anti_recency` selection mode, how long it takes for a played sound to get back its full weight
+    #[serde(default = "default_recovery_seconds")]
+    recovery_seconds: usize,
+    /// Write the next play to the next-play file, so that the schedule survives a restart.
+    /// Turning this off avoids writing to disk on every play, e.g. on flash storage or in containers,
+    /// but a restart throws away the scheduled play and schedules a new one.
+    #[serde(default = "default_persist_schedule")]
+    persist_schedule: bool,
+    /// A scheduled play that was missed by more than this many seconds, e.g. because the speaker was off,
+    /// is skipped and a new one scheduled instead of playing it late. By default, missed plays are always played.
+    stale_play_grace_seconds: Option<u64>,
+    /// Don't play anything on schedule until this many seconds after starting, e.g. so that the speaker
+    /// doesn't go off right after booting. Plays requested through commands are not delayed.
+    #[serde(default)]
+    startup_delay_seconds: u64,
+    /// If the next play ends up further away than this, e.g. because of narrow windows and a large `upper_bound`,
+    /// a new play is scheduled once this much time has passed instead of waiting for it
+    max_lookahead_seconds: Option<u64>,
+    /// How the time between plays is distributed between `lower_bound` and `upper_bound`
+    #[serde(default)]
+    distribution: Distribution,
+    /// Mean of the normal or exponential distribution, defaults to halfway between the bounds
+    mean: Option<f32>,
+    /// Standard deviation of the normal distribution, defaults to a quarter of the distance between the bounds
+    std_dev: Option<f32>,
+}
+
+impl General {
+    /// The configured distribution of the time between plays
+    fn sampler(&self) -> Result<Sampler, String> {
+        Sampler::new(
+            self.distribution,
+            self.lower_bound as f32,
+            self.upper_bound as f32,
+            self.mean,
+            self.std_dev,
+        )
+    }
+
+    /// Picks the number of seconds until the next play, according to the configured distribution
+    fn sample_interval(&self, rng: &mut (impl Rng + ?Sized)) -> f32 {
+        match self.sampler() {
+            Ok(sampler) => sampler.sample(rng),
+            // Only reached with a config that wasn't validated, as invalid distributions are rejected on load
+            Err(e) => {
+                warn!("Invalid distribution, waiting for lower_bound: {e}");
+                self.lower_bound as f32
+            }
+        }
+    }
+
+    /// Master volume at the given time of day, interpolated linearly between the points of `volume_schedule`.
+    /// The schedule wraps around midnight, so the last point leads into the first one.
+    fn volume_at(&self, time: NaiveTime) -> f32 {
+        const DAY: i64 = 24 * 60 * 60;
+
+        let mut points = self.volume_schedule.clone();
+        points.sort_by_key(|point| point.time);
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return 1.0;
+        };
+
+        let prev = points
+            .iter()
+            .rev()
+            .find(|point| point.time <= time)
+            .unwrap_or(last);
+        let next = points
+            .iter()
+            .find(|point| point.time > time)
+            .unwrap_or(first);
+
+        let seconds = |time: NaiveTime| time.num_seconds_from_midnight() as i64;
+        let span = (seconds(next.time) - seconds(prev.time)).rem_euclid(DAY);
+        if span == 0 {
+            return prev.volume;
+        }
+        let progress = (seconds(time) - seconds(prev.time)).rem_euclid(DAY) as f32 / span as f32;
+        prev.volume + (next.volume - prev.volume) * progress
+    }
+
+    /// Converts a point in time to local time in the configured timezone
+    fn to_local(&self, time: DateTime<FixedOffset>) -> NaiveDateTime {
+        match self.timezone {
+            Some(tz) => time.with_timezone(&tz).naive_local(),
+            None => time.with_timezone(&Local).naive_local(),
+        }
+    }
+
+    /// Converts a local time in the configured timezone to an unambiguous point in time
+    fn localize(&self, time: NaiveDateTime) -> DateTime<FixedOffset> {
+        match self.timezone {
+            Some(tz) => localize(&tz, time),
+            None => localize(&Local, time),
+        }
+    }
+}
+
+const fn default_no_repeat_history() -> usize {
+    1
+}
+
+/// Deserializes either a number of seconds or a human readable duration like `"1h30m"` into seconds
+fn deserialize_seconds<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<usize, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seconds {
+        Seconds(usize),
+        Duration(String),
+    }
+
+    match Seconds::deserialize(deserializer)? {
+        Seconds::Seconds(seconds) => Ok(seconds),
+        Seconds::Duration(duration) => humantime::parse_duration(&duration)
+            .map(|duration| duration.as_secs() as usize)
+            .map_err(|e| serde::de::Error::custom(format!("invalid duration {duration:?}: {e}"))),
+    }
+}
+
+/// Deserializes an optional value like [`deserialize_seconds`], for fields that default to `None`
+fn deserialize_optional_seconds<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    deserialize_seconds(deserializer).map(|seconds| Some(seconds as u64))
+}
+
+const fn default_normalize_target() -> f32 {
+    0.1
+}
+
+fn default_announce_command() -> Vec<String> {
+    vec!["espeak".into(), "--stdout".into()]
+}
+
+const fn default_persist_schedule() -> bool {
+    true
+}
+
+const fn default_recovery_seconds() -> usize {
+    24 * 60 * 60
+}
+
+/// One of the `sound_dirs`, either just a path or a path with a weight
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum SoundDir {
+    Path(PathBuf),
+    Weighted {
+        path: PathBuf,
+        #[serde(default = "default_weight")]
+        weight: f32,
+    },
+}
+
+impl SoundDir {
+    fn path(&self) -> &Path {
+        match self {
+            SoundDir::Path(path) | SoundDir::Weighted { path, .. } => path,
+        }
+    }
+
+    fn weight(&self) -> f32 {
+        match self {
+            SoundDir::Path(_) => default_weight(),
+            SoundDir::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ScheduleMode {
+    /// Sounds play at random intervals between the bounds, as well as at any `fixed_times`
+    #[default]
+    Random,
+    /// Sounds only play at the `fixed_times` of the schedule
+    Timetable,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+struct VolumePoint {
+    time: NaiveTime,
+    volume: f32,
+}
+
+/// What to do when it is time to play while the previous sound is still playing
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum OverrunPolicy {
+    /// Play the new sound after the current one, and push the following play to after it has ended
+    #[default]
+    Queue,
+    /// Stop the current sound and play the new one right away
+    Truncate,
+    /// Don't play the new sound, and schedule another play instead
+    Skip,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SelectionMode {
+    /// Sounds are picked according to their configured weight
+    #[default]
+    Weighted,
+    /// Sounds that were played recently have their weight reduced,
+    /// recovering linearly to their configured weight over `recovery_seconds`
+    AntiRecency,
+    /// First a directory is picked according to its `folder_weight`, no matter how many sounds it has,
+    /// and then a sound in it according to their configured weight
+    Folder,
+    /// Every sound plays once, picked according to its configured weight,
+    /// before any of them plays again
+    ShuffleBag,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(try_from = "ScheduleEntries")]
+pub struct Schedule {
+    /// Valid time windows for each day, indexed by `Weekday::num_days_from_monday`
+    days: [Vec<TimeWindow>; 7],
+    /// Dates on which nothing in this entry should play, regardless of weekday
+    blackout_dates: Vec<Blackout>,
+    /// Times of day at which a sound plays on each day, indexed like `days`, in addition to the random plays
+    fixed_times: [Vec<NaiveTime>; 7],
+    /// Sounds to play at `fixed_times` instead of the regular ones
+    fixed_sounds_dir: Option<PathBuf>,
+    /// Only weeks with an even or odd ISO week number are scheduled, if set
+    week_parity: Option<WeekParity>,
+    /// Cron expressions whose matching minutes are valid in addition to the windows of `days`
+    cron: Vec<Cron>,
+    /// Whether the calendar events block or add valid time
+    calendar_mode: CalendarMode,
+    /// Start and end of the events in the calendar, which are not part of the config but fetched while running
+    calendar_events: Vec<(NaiveDateTime, NaiveDateTime)>,
+    /// The `[[schedule]]` entries after the first, whose windows and fixed times are combined with these
+    /// while their blackout dates and week parity only apply to themselves
+    entries: Vec<Schedule>,
+}
+
+/// Every other week, by ISO week number. Years with 53 weeks end with an odd week, so that week and the next are both odd.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum WeekParity {
+    Even,
+    Odd,
+}
+
+impl WeekParity {
+    fn contains(self, date: NaiveDate) -> bool {
+        let even = date.iso_week().week().is_multiple_of(2);
+        even == (self == WeekParity::Even)
+    }
+}
+
+impl Schedule {
+    /// This entry followed by the other `entries`
+    fn all_entries(&self) -> impl Iterator<Item = &Schedule> {
+        std::iter::once(self).chain(&self.entries)
+    }
+
+    /// Whether no day has any windows or fixed times
+    fn is_empty(&self) -> bool {
+        self.all_entries().all(|entry| {
+            entry.days.iter().all(|windows| windows.is_empty()) && entry.cron.is_empty()
+        }) && !self.has_fixed_times()
+    }
+
+    /// Whether any day has fixed times
+    fn has_fixed_times(&self) -> bool {
+        self.all_entries()
+            .flat_map(|entry| &entry.fixed_times)
+            .any(|times| !times.is_empty())
+    }
+
+    /// Whether anything may play on the given date, either in a window or at a fixed time
+    fn is_scheduled(&self, date: NaiveDate) -> bool {
+        !self.windows(date).is_empty() || !self.fixed_times_on(date).is_empty()
+    }
+
+    /// Whether nothing in this entry plays on the given date because of `blackout_dates` or `week_parity`
+    fn is_excluded(&self, date: NaiveDate) -> bool {
+        self.blackout_dates
+            .iter()
+            .any(|blackout| blackout.contains(date))
+            || self
+                .week_parity
+                .is_some_and(|parity| !parity.contains(date))
+    }
+
+    /// Returns the valid time windows on the given date
+    fn windows(&self, date: NaiveDate) -> Cow<'_, [TimeWindow]> {
+        let windows = self.entry_windows(date);
+        if self.calendar_events.is_empty() {
+            return windows;
+        }
+        let mut windows = windows.into_owned();
+
+        // The parts of the events on this date, as the first and last time they cover
+        let day_start = date.and_time(NaiveTime::MIN);
+        let day_last = day_start + TimeDelta::days(1) - TimeDelta::nanoseconds(1);
+        let events = self.calendar_events.iter().filter_map(|(start, end)| {
+            let start = (*start).max(day_start);
+            let last = (*end - TimeDelta::nanoseconds(1)).min(day_last);
+            (start <= last).then(|| (start.time(), last.time()))
+        });
+        match self.calendar_mode {
+            CalendarMode::Busy => {
+                for (start, last) in events {
+                    windows = windows
+                        .iter()
+                        .flat_map(|window| window.without(start, last))
+                        .collect();
+                }
+            }
+            CalendarMode::Extra => {
+                windows.extend(events.map(|(start_time, end_time)| TimeWindow {
+                    start_time,
+                    end_time,
+                    tags: Vec::new(),
+                    sounds_dir: None,
+                }))
+            }
+        }
+
+        windows.sort_by_key(|window| window.start_time);
+        Cow::Owned(windows)
+    }
+
+    /// Returns the windows of this entry and the other `entries` on the given date, without the calendar events
+    fn entry_windows(&self, date: NaiveDate) -> Cow<'_, [TimeWindow]> {
+        let excluded = self.is_excluded(date);
+        let windows: &[TimeWindow] = if excluded {
+            &[]
+        } else {
+            &self.days[date.weekday().num_days_from_monday() as usize]
+        };
+        if (excluded || self.cron.is_empty()) && self.entries.is_empty() {
+            return Cow::Borrowed(windows);
+        }
+
+        let mut windows = windows.to_vec();
+        if !excluded {
+            windows.extend(self.cron.iter().flat_map(|cron| cron.windows(date)).map(
+                |(start_time, end_time)| TimeWindow {
+                    start_time,
+                    end_time,
+                    tags: Vec::new(),
+                    sounds_dir: None,
+                },
+            ));
+        }
+        for entry in &self.entries {
+            windows.extend(entry.entry_windows(date).iter().cloned());
+        }
+        windows.sort_by_key(|window| window.start_time);
+        Cow::Owned(windows)
+    }
+
+    /// Replaces the calendar events, given as local times
+    fn set_calendar(&mut self, mode: CalendarMode, events: Vec<(NaiveDateTime, NaiveDateTime)>) {
+        self.calendar_mode = mode;
+        self.calendar_events = events;
+    }
+
+    /// Whether sounds may play at the given time. Windows include both their start and end time.
+    pub fn is_time_valid(&self, time: NaiveDateTime) -> bool {
+        self.active_window(time).is_some()
+    }
+
+    /// Returns the window the given time is in, if any
+    fn active_window(&self, time: NaiveDateTime) -> Option<TimeWindow> {
+        self.windows(time.date())
+            .iter()
+            .find(|window| window.contains(time.time()))
+            .cloned()
+    }
+
+    /// Returns the day whose windows the given time is in, which for a window continuing past midnight
+    /// is the day it started on. Times outside the windows are on their own date.
+    fn play_day(&self, time: NaiveDateTime) -> NaiveDate {
+        let date = time.date();
+        let last = NaiveTime::from_hms_nano_opt(23, 59, 59, 999_999_999).unwrap();
+        let continued = self
+            .active_window(time)
+            .is_some_and(|window| window.start_time == NaiveTime::MIN);
+        match date.pred_opt() {
+            Some(yesterday) if continued && self.is_time_valid(yesterday.and_time(last)) => {
+                yesterday
+            }
+            _ => date,
+        }
+    }
+
+    /// Returns the first valid time on a later day than `day`, as given by [`Self::play_day`]
+    fn next_play_day_start(&self, day: NaiveDate) -> Option<NaiveDateTime> {
+        let next = self.find_next_valid_time(day.succ_opt()?.and_time(NaiveTime::MIN))?;
+        if self.play_day(next) > day {
+            return Some(next);
+        }
+        // The window of `day` continues past midnight, so the next day starts after it
+        let window = self.active_window(next)?;
+        self.find_next_valid_time(next.date().and_time(window.end_time) + TimeDelta::nanoseconds(1))
+    }
+
+    /// Returns the fixed times of this entry and the other `entries` on the given date, in order
+    fn fixed_times_on(&self, date: NaiveDate) -> Cow<'_, [NaiveTime]> {
+        let times: &[NaiveTime] = if self.is_excluded(date) {
+            &[]
+        } else {
+            &self.fixed_times[date.weekday().num_days_from_monday() as usize]
+        };
+        if self.entries.is_empty() {
+            return Cow::Borrowed(times);
+        }
+
+        let mut times = times.to_vec();
+        for entry in &self.entries {
+            times.extend(entry.fixed_times_on(date).iter());
+        }
+        times.sort();
+        times.dedup();
+        Cow::Owned(times)
+    }
+
+    /// Returns the first of the `fixed_times` after `time`,
+    /// or `None` if there is none within `MAX_SEARCH_DAYS`
+    fn next_fixed_time(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        if !self.has_fixed_times() {
+            return None;
+        }
+
+        let mut date = time.date();
+        for _ in 0..=MAX_SEARCH_DAYS {
+            let next = self
+                .fixed_times_on(date)
+                .iter()
+                .map(|fixed| NaiveDateTime::new(date, *fixed))
+                .find(|fixed| *fixed > time);
+            if next.is_some() {
+                return next;
+            }
+            date += chrono::Duration::days(1);
+        }
+        None
+    }
+
+    /// Whether a play at `time` is one of the `fixed_times`
+    fn is_fixed_time(&self, time: NaiveDateTime) -> bool {
+        self.fixed_times_on(time.date()).contains(&time.time())
+    }
+
+    /// Returns the time at which `offset` of valid time has passed since `time`, skipping all invalid time in between.
+    /// The offset may span any number of windows and days, e.g. an offset of 3 hours from the start of a 2 hour window
+    /// lands 1 hour into the next window.
+    /// Returns `None` if `MAX_SEARCH_DAYS` days in a row pass without any window.
+    ///
+    /// Local times are treated as if there were no DST transitions, see [`Self::add_valid_time_in`].
+    pub fn add_valid_time(&self, time: NaiveDateTime, offset: TimeDelta) -> Option<NaiveDateTime> {
+        self.add_valid_time_in(&Utc, time, offset)
+    }
+
+    /// Like [`Self::add_valid_time`], but counting the time that actually passes in the timezone `tz`,
+    /// so that a window the clock is turned forward in is an hour shorter and one it is turned back in is an hour longer.
+    ///
+    /// A result in the second pass of the hour that is repeated when the clock is turned back
+    /// would be read back as the first pass, so it is moved forward by the repeated hour instead.
+    pub fn add_valid_time_in<Tz: TimeZone>(
+        &self,
+        tz: &Tz,
+        time: NaiveDateTime,
+        mut offset: TimeDelta,
+    ) -> Option<NaiveDateTime> {
+        // Valid time before this has already been counted, which also keeps overlapping windows from counting twice
+        let mut cursor = time;
+        let mut date = time.date();
+        let mut empty_days = 0;
+        while empty_days <= MAX_SEARCH_DAYS {
+            let windows = self.windows(date);
+            if windows.is_empty() {
+                empty_days += 1;
+            } else {
+                empty_days = 0;
+            }
+
+            for window in windows.iter() {
+                let start = NaiveDateTime::new(date, window.start_time).max(cursor);
+                let end = NaiveDateTime::new(date, window.end_time);
+                if end < start {
+                    continue;
+                }
+                // Skipped local times are moved past the gap, so a window starting in it starts when the clock does
+                let (real_start, real_end) = (localize(tz, start), localize(tz, end));
+                let length = (real_end - real_start).max(TimeDelta::zero());
+                if offset <= length {
+                    let then = (real_start + offset).with_timezone(tz);
+                    return Some(match tz.from_local_datetime(&then.naive_local()) {
+                        LocalResult::Ambiguous(first, second) if then != first => {
+                            (then + (second - first)).naive_local()
+                        }
+                        _ => then.naive_local(),
+                    });
+                }
+                offset -= length;
+                cursor = end;
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    /// Returns `time` if it is valid, otherwise the end of the latest window before it.
+    ///
+    /// After the end of a window this is the end of that same window, even on the same day,
+    /// while before the first window of a day it is the end of the last window on an earlier day.
+    /// Returns `None` if there is no window within `MAX_SEARCH_DAYS`.
+    pub fn find_last_valid_time(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        if self.is_time_valid(time) {
+            return Some(time);
+        }
+
+        // Find last previous valid time, which is the end of the latest window that has already ended
+        let mut date = time.date();
+        for _ in 0..=MAX_SEARCH_DAYS {
+            let last_end = self
+                .windows(date)
+                .iter()
+                .map(|window| NaiveDateTime::new(date, window.end_time))
+                .filter(|end| *end < time)
+                .max();
+            if last_end.is_some() {
+                return last_end;
+            }
+            date -= chrono::Duration::days(1);
+        }
+        None
+    }
+
+    /// Returns `time` if it is valid, otherwise the start of the earliest window after it.
+    ///
+    /// Before the start of a window this is the start of that same window, even on the same day,
+    /// while after the last window of a day it is the start of the first window on a later day.
+    /// Returns `None` if there is no window within `MAX_SEARCH_DAYS`.
+    pub fn find_next_valid_time(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        if self.is_time_valid(time) {
+            return Some(time);
+        }
+
+        // Find next valid time, which is the start of the earliest window that has not yet started
+        let mut date = time.date();
+        for _ in 0..=MAX_SEARCH_DAYS {
+            let next_start = self
+                .windows(date)
+                .iter()
+                .map(|window| NaiveDateTime::new(date, window.start_time))
+                .filter(|start| *start > time)
+                .min();
+            if next_start.is_some() {
+                return next_start;
+            }
+            date += chrono::Duration::days(1);
+        }
+        None
+    }
+}
+
+/// How many days to search for a valid time before giving up.
+/// Long enough to get past any realistic vacation in `blackout_dates`,
+/// while still giving up quickly on schedules where nothing is ever valid.
+const MAX_SEARCH_DAYS: usize = 366;
+
+/// Picks the time of the next play when scheduling at `from`,
+/// or `None` if the schedule has no valid time to play at or only plays at fixed times
+pub fn next_play_time(
+    from: NaiveDateTime,
+    config: &BaseConfig,
+    rng: &mut (impl Rng + ?Sized),
+) -> Option<NaiveDateTime> {
+    let schedule = &config.schedule;
+    // The fixed times are scheduled separately, and there is nothing else in timetable mode
+    if config.general.mode == ScheduleMode::Timetable {
+        return None;
+    }
+
+    // First, find out if the current time is a valid time.
+    // If it isn't, we schedule our next play as if the last valid time is when the scheduling occured.
+    // This allows the sound to be scheduled the same way no matter if we just started in the middle of the night
+    // or we just played a sound, without sounds starting playing the instant we reach a valid time.
+    let current_time = schedule.find_last_valid_time(from)?;
+
+    // Generate a new time for play
+    let seconds_from_now = config.general.sample_interval(rng);
+
+    // The interval only counts valid time, pretending that invalid time is simply cut out from reality
+    let offset = TimeDelta::from_std(Duration::from_secs_f32(seconds_from_now)).ok()?;
+    match config.general.timezone {
+        Some(tz) => schedule.add_valid_time_in(&tz, current_time, offset),
+        None => schedule.add_valid_time_in(&Local, current_time, offset),
+    }
+}
+
+/// Pushes a play scheduled at `then` forward so that it is at least `min_gap_seconds` after `last_play`
+fn keep_min_gap(
+    config: &BaseConfig,
+    then: NaiveDateTime,
+    last_play: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    let earliest = last_play + TimeDelta::seconds(config.general.min_gap_seconds as i64);
+    if then < earliest {
+        config.schedule.find_next_valid_time(earliest)
+    } else {
+        Some(then)
+    }
+}
+
+/// Prints the next `count` play times starting at `from`, without waiting for them
+pub fn simulate(
+    config_path: &Path,
+    from: Option<NaiveDateTime>,
+    count: usize,
+    seed: Option<u64>,
+) -> anyhow::Result<()> {
+    let base_config = BaseConfig::load(config_path)?;
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let clock = ManualClock::new(match from {
+        Some(from) => base_config.general.localize(from).to_utc(),
+        None => Utc::now(),
+    });
+    let mut last_play = None;
+    let mut printed = 0;
+    let mut switches = 0;
+    while printed < count {
+        let today = base_config
+            .general
+            .to_local(clock.now().fixed_offset())
+            .date();
+        let (config, profile) = base_config.for_date(today);
+        let now = config.general.to_local(clock.now().fixed_offset());
+        let mut then = next_play_time(now, &config, &mut rng);
+        if let (Some(next), Some(last_play)) = (then, last_play) {
+            then = keep_min_gap(&config, next, last_play);
+        }
+        if let Some(fixed) = config.schedule.next_fixed_time(now) {
+            if then.is_none_or(|then| fixed <= then) {
+                then = Some(fixed);
+            }
+        }
+        let Some(then) = then else {
+            println!("No valid time within {MAX_SEARCH_DAYS} days");
+            break;
+        };
+
+        // Like when running for real, a play scheduled past a change of profile is rescheduled at midnight
+        let tomorrow = today.succ_opt().unwrap_or(today);
+        if then.date() > today && base_config.for_date(tomorrow).1 != profile {
+            switches += 1;
+            if switches > MAX_SEARCH_DAYS {
+                println!("No valid time within {MAX_SEARCH_DAYS} days");
+                break;
+            }
+            clock.set(
+                base_config
+                    .general
+                    .localize(tomorrow.and_time(NaiveTime::MIN))
+                    .to_utc(),
+            );
+            continue;
+        }
+        switches = 0;
+
+        if config.schedule.is_fixed_time(then) {
+            println!("{then} (fixed)");
+        } else {
+            println!("{then}");
+        }
+        printed += 1;
+
+        // Assume every play happens, and the next one is scheduled right after
+        last_play = Some(then);
+        clock.set(config.general.localize(then).to_utc());
+    }
+
+    Ok(())
+}
+
+/// Sends `action` to the instance running with the config at `config_path`, and prints its status afterwards
+pub async fn control(config_path: &Path, action: CtlAction) -> anyhow::Result<()> {
+    let base_config = BaseConfig::load(config_path)?;
+    let Some(socket) = base_config.status_socket() else {
+        anyhow::bail!("the config has no [status] socket to control the running instance through");
+    };
+    let socket = socket?;
+    let command = match action {
+        CtlAction::PlayNow => Some("play"),
+        CtlAction::Skip | CtlAction::Reschedule => Some("reschedule"),
+        CtlAction::Status => None,
+    };
+    let status = status::control(&socket, command).await?;
+
+    match (status.next_play, status.seconds_remaining) {
+        (Some(time), Some(remaining)) => println!("Next play @ {time}, in {remaining} seconds"),
+        _ => println!("No play scheduled"),
+    }
+    if let Some(profile) = &status.profile {
+        println!("Profile: {profile}");
+    }
+    if let Some(last_played) = &status.last_played {
+        println!("Last played: {}", last_played.display());
+    }
+    println!("Plays today: {}", status.plays_today);
+    if status.volume != 1.0 {
+        println!("Volume: {}", status.volume);
+    }
+    if status.paused {
+        println!("Paused");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct TimeWindow {
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+    /// Only sounds with at least one of these tags are played during the window. If empty, all sounds are played.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Directory to pick sounds from during the window, instead of the global one
+    sounds_dir: Option<PathBuf>,
+}
+
+impl TimeWindow {
+    /// Whether `time` is within the window, which includes both its start and its end
+    fn contains(&self, time: NaiveTime) -> bool {
+        (self.start_time..=self.end_time).contains(&time)
+    }
+
+    /// Returns what is left of the window when the time from `start` to `last` is cut out of it
+    fn without(&self, start: NaiveTime, last: NaiveTime) -> Vec<TimeWindow> {
+        if last < self.start_time || start > self.end_time {
+            return vec![self.clone()];
+        }
+        let mut parts = vec![];
+        if start > self.start_time {
+            parts.push(TimeWindow {
+                end_time: start - TimeDelta::nanoseconds(1),
+                ..self.clone()
+            });
+        }
+        if last < self.end_time {
+            parts.push(TimeWindow {
+                start_time: last + TimeDelta::nanoseconds(1),
+                ..self.clone()
+            });
+        }
+        parts
+    }
+}
+
+/// Either a single date, e.g. `2024-12-24`, or an inclusive range of dates, e.g. `{ start = 2024-07-01, end = 2024-07-31 }`.
+/// Dates may also be quoted. Dates without a year, e.g. `"12-25"` or `{ start = "12-24", end = "01-01" }`, recur every year.
+#[derive(Debug, Clone, Copy)]
+enum Blackout {
+    Date(NaiveDate),
+    Range { start: NaiveDate, end: NaiveDate },
+    Yearly(MonthDay),
+    YearlyRange { start: MonthDay, end: MonthDay },
+}
+
+impl Blackout {
+    fn contains(&self, date: NaiveDate) -> bool {
+        match *self {
+            Blackout::Date(blackout) => date == blackout,
+            Blackout::Range { start, end } => (start..=end).contains(&date),
+            Blackout::Yearly(blackout) => MonthDay::of(date) == blackout,
+            Blackout::YearlyRange { start, end } => {
+                let day = MonthDay::of(date);
+                // A range ending before it starts wraps around the new year
+                if start <= end {
+                    (start..=end).contains(&day)
+                } else {
+                    day >= start || day <= end
+                }
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Blackout {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Going through a TOML value, so that both date literals and strings are accepted
+        let value = toml::Value::deserialize(deserializer)?;
+        let blackout = match &value {
+            toml::Value::Table(table) => {
+                if let Some(key) = table.keys().find(|key| *key != "start" && *key != "end") {
+                    return Err(serde::de::Error::custom(format!(
+                        "unknown field {key:?} in blackout range, expected start and end"
+                    )));
+                }
+                let get = |key| {
+                    table
+                        .get(key)
+                        .ok_or_else(|| format!("blackout range is missing {key}"))
+                        .and_then(parse_blackout_date)
+                };
+                match (get("start"), get("end")) {
+                    (Ok(Blackout::Date(start)), Ok(Blackout::Date(end))) => {
+                        Ok(Blackout::Range { start, end })
+                    }
+                    (Ok(Blackout::Yearly(start)), Ok(Blackout::Yearly(end))) => {
+                        Ok(Blackout::YearlyRange { start, end })
+                    }
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                    _ => Err(
+                        "blackout range must have a year on both start and end, or on neither"
+                            .to_owned(),
+                    ),
+                }
+            }
+            value => parse_blackout_date(value),
+        };
+        blackout.map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parses a single date of a blackout, into either [`Blackout::Date`] or [`Blackout::Yearly`]
+fn parse_blackout_date(value: &toml::Value) -> Result<Blackout, String> {
+    match value {
+        toml::Value::Datetime(datetime) => match datetime {
+            toml::value::Datetime {
+                date: Some(date),
+                time: None,
+                offset: None,
+            } => NaiveDate::from_ymd_opt(date.year.into(), date.month.into(), date.day.into())
+                .map(Blackout::Date)
+                .ok_or_else(|| format!("invalid blackout date {datetime}")),
+            _ => Err(format!(
+                "blackout date {datetime} must be a date without a time"
+            )),
+        },
+        toml::Value::String(string) => match string.parse() {
+            Ok(date) => Ok(Blackout::Date(date)),
+            Err(_) => MonthDay::try_from(string.clone())
+                .map(Blackout::Yearly)
+                .map_err(|_| {
+                    format!(
+                        "invalid blackout date {string:?}, expected \"YYYY-MM-DD\" or \"MM-DD\""
+                    )
+                }),
+        },
+        value => Err(format!(
+            "invalid blackout date {value}, expected a date like 2024-12-24 or \"12-24\""
+        )),
+    }
+}
+
+/// A day of the year, written as `"MM-DD"`
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+struct MonthDay {
+    month: u32,
+    day: u32,
+}
+
+impl MonthDay {
+    fn of(date: NaiveDate) -> Self {
+        MonthDay {
+            month: date.month(),
+            day: date.day(),
+        }
+    }
+}
+
+impl TryFrom<String> for MonthDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid date {value:?}, expected \"MM-DD\"");
+        let (month, day) = value.split_once('-').ok_or_else(invalid)?;
+        let month = month.parse().map_err(|_| invalid())?;
+        let day = day.parse().map_err(|_| invalid())?;
+        // Checked against a leap year, so that February 29th is allowed
+        NaiveDate::from_ymd_opt(2000, month, day).ok_or_else(invalid)?;
+        Ok(MonthDay { month, day })
+    }
+}
+
+/// Deserializes a day, a list of days, or shorthands for several days into the days they stand for.
+/// Days can be given as names like `"monday"` or `"mon"`, ranges like `"mon-fri"` or `"fri-mon"`,
+/// or one of `"weekdays"`, `"weekends"` and `"everyday"`.
+fn deserialize_weekdays<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Weekday>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Days {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let tokens = match Days::deserialize(deserializer)? {
+        Days::One(token) => vec![token],
+        Days::Many(tokens) => tokens,
+    };
+
+    let mut days = vec![];
+    for token in tokens {
+        let expanded = parse_weekdays(&token).ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "invalid weekday {token:?}, expected a day like \"monday\", a range like \"mon-fri\", \"weekdays\", \"weekends\" or \"everyday\""
+            ))
+        })?;
+        days.extend(expanded);
+    }
+    Ok(days)
+}
+
+fn parse_weekdays(token: &str) -> Option<Vec<Weekday>> {
+    let range = |start: Weekday, end: Weekday| {
+        let mut days = vec![start];
+        let mut day = start;
+        while day != end {
+            day = day.succ();
+            days.push(day);
+        }
+        days
+    };
+
+    match token.trim().to_lowercase().as_str() {
+        "weekdays" => Some(range(Weekday::Mon, Weekday::Fri)),
+        "weekends" | "weekend" => Some(range(Weekday::Sat, Weekday::Sun)),
+        "everyday" | "daily" => Some(range(Weekday::Mon, Weekday::Sun)),
+        token => match token.split_once('-') {
+            Some((start, end)) => Some(range(start.trim().parse().ok()?, end.trim().parse().ok()?)),
+            None => Some(vec![token.parse().ok()?]),
+        },
+    }
+}
+
+/// The schedule as written in the config file.
+///
+/// Every day in `weekdays` gets the same windows, given either as a single `start_time` and `end_time`
+/// or as a list of disjoint `windows`, e.g. `[{ start_time = "8:00", end_time = "12:00" }, { start_time = "13:00", end_time = "17:00" }]`.
+/// A day can also be given its own list of windows, e.g. `saturday = [{ start_time = "10:00", end_time = "14:00" }]`,
+/// or a single window as a `[schedule.saturday]` table, which replaces the shared windows for that day.
+/// A window with an `end_time` before its `start_time`, e.g. from `"22:00"` to `"02:00"`, continues past midnight into the next day.
+/// Nothing in the entry plays on dates listed in `blackout_dates`, while other `[[schedule]]` entries still do.
+/// `fixed_times` play on the days in `weekdays` and the days given their own windows, which in timetable mode
+/// don't need any windows, e.g. `weekdays = "mon"` with `fixed_times = ["10:00"]`.
+/// Instead of windows, the valid time can be given as a `cron` expression, see [`Cron`].
+/// `weekdays` also accepts ranges and shorthands, e.g. `["weekdays", "sat"]`, see [`deserialize_weekdays`].
+#[derive(Debug, Deserialize)]
+struct ScheduleConfig {
+    /// Name of the entry when there are several, to tell them apart in errors
+    name: Option<String>,
+    #[serde(default, alias = "exclude_dates")]
+    blackout_dates: Vec<Blackout>,
+    #[serde(default, deserialize_with = "deserialize_weekdays")]
+    weekdays: Vec<Weekday>,
+    start_time: Option<NaiveTime>,
+    end_time: Option<NaiveTime>,
+    #[serde(default)]
+    windows: Vec<TimeWindow>,
+    #[serde(default)]
+    fixed_times: Vec<NaiveTime>,
+    fixed_sounds_dir: Option<PathBuf>,
+    week_parity: Option<WeekParity>,
+    cron: Option<Cron>,
+    #[serde(flatten)]
+    days: HashMap<Weekday, DayWindows>,
+}
+
+/// The windows of a single day, either a list of windows or a single `[schedule.monday]` table
+#[derive(Debug)]
+struct DayWindows(Vec<TimeWindow>);
+
+impl<'de> Deserialize<'de> for DayWindows {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DayVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for DayVisitor {
+            type Value = DayWindows;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a window or a list of windows")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                TimeWindow::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                    .map(|window| DayWindows(vec![window]))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                Vec::deserialize(serde::de::value::SeqAccessDeserializer::new(seq)).map(DayWindows)
+            }
+        }
+
+        deserializer.deserialize_any(DayVisitor)
+    }
+}
+
+/// The schedule as written in the config, either a single `[schedule]` table
+/// or several `[[schedule]]` tables, e.g. one for weekday mornings and one for weekend afternoons,
+/// where sounds may play during the windows of any of them
+enum ScheduleEntries {
+    Single(Box<ScheduleConfig>),
+    Multiple(Vec<ScheduleConfig>),
+}
+
+impl<'de> Deserialize<'de> for ScheduleEntries {
+    // Not an untagged enum, since that would hide the actual error in a single schedule
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for EntriesVisitor {
+            type Value = ScheduleEntries;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a schedule table or an array of schedule tables")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                ScheduleConfig::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                    .map(|config| ScheduleEntries::Single(Box::new(config)))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                Vec::deserialize(serde::de::value::SeqAccessDeserializer::new(seq))
+                    .map(ScheduleEntries::Multiple)
+            }
+        }
+
+        deserializer.deserialize_any(EntriesVisitor)
+    }
+}
+
+impl TryFrom<ScheduleEntries> for Schedule {
+    type Error = String;
+
+    fn try_from(entries: ScheduleEntries) -> Result<Self, Self::Error> {
+        let configs = match entries {
+            ScheduleEntries::Single(config) => return Schedule::try_from(*config),
+            ScheduleEntries::Multiple(configs) => configs,
+        };
+
+        let mut schedule: Option<Schedule> = None;
+        for (i, config) in configs.into_iter().enumerate() {
+            let name = config.name.clone().unwrap_or_else(|| format!("#{}", i + 1));
+            let entry = Schedule::try_from(config).map_err(|e| format!("schedule {name}: {e}"))?;
+            match &mut schedule {
+                Some(schedule) => schedule
+                    .merge(entry)
+                    .map_err(|e| format!("schedule {name}: {e}"))?,
+                None => schedule = Some(entry),
+            }
+        }
+        Ok(schedule.unwrap_or_default())
+    }
+}
+
+impl Schedule {
+    /// Adds `other` as another entry of this schedule. Its windows and fixed times are combined with the others,
+    /// while its blackout dates and week parity only apply to its own windows and fixed times.
+    fn merge(&mut self, mut other: Schedule) -> Result<(), String> {
+        if self.fixed_sounds_dir.is_some()
+            && other.fixed_sounds_dir.is_some()
+            && self.fixed_sounds_dir != other.fixed_sounds_dir
+        {
+            return Err("`fixed_sounds_dir` differs from an earlier schedule".into());
+        }
+        self.fixed_sounds_dir = self
+            .fixed_sounds_dir
+            .take()
+            .or(other.fixed_sounds_dir.take());
+        self.entries.push(other);
+        Ok(())
+    }
+}
+
+impl TryFrom<ScheduleConfig> for Schedule {
+    type Error = String;
+
+    fn try_from(config: ScheduleConfig) -> Result<Self, Self::Error> {
+        let mut fixed_times = config.fixed_times;
+        fixed_times.sort();
+        fixed_times.dedup();
+        let fixed_days: HashSet<Weekday> = config
+            .weekdays
+            .iter()
+            .chain(config.days.keys())
+            .copied()
+            .collect();
+        if !fixed_times.is_empty() && fixed_days.is_empty() {
+            return Err("`fixed_times` requires `weekdays` to play on".into());
+        }
+        let mut schedule = Schedule {
+            blackout_dates: config.blackout_dates,
+            fixed_sounds_dir: config.fixed_sounds_dir,
+            week_parity: config.week_parity,
+            cron: config.cron.into_iter().collect(),
+            ..Default::default()
+        };
+
+        let mut shared = config.windows;
+        match (config.start_time, config.end_time) {
+            (Some(start_time), Some(end_time)) => shared.push(TimeWindow {
+                start_time,
+                end_time,
+                tags: Vec::new(),
+                sounds_dir: None,
+            }),
+            (None, None) => {}
+            _ => return Err("`start_time` and `end_time` must be specified together".into()),
+        }
+        // Without windows, the weekdays only pick the days of the fixed times
+        if !config.weekdays.is_empty() && shared.is_empty() && fixed_times.is_empty() {
+            return Err(
+                "`weekdays` requires either `start_time` and `end_time`, `windows` or `fixed_times`"
+                    .into(),
+            );
+        }
+        shared.sort_by_key(|window| window.start_time);
+
+        for day in config.weekdays {
+            schedule.days[day.num_days_from_monday() as usize] = shared.clone();
+        }
+        for day in fixed_days {
+            schedule.fixed_times[day.num_days_from_monday() as usize] = fixed_times.clone();
+        }
+
+        for (day, DayWindows(mut windows)) in config.days {
+            windows.sort_by_key(|window| window.start_time);
+            schedule.days[day.num_days_from_monday() as usize] = windows;
+        }
+
+        // A window ending before it starts goes past midnight, so the rest of it is on the next day
+        let mut carried: [Vec<TimeWindow>; 7] = Default::default();
+        for (day, windows) in schedule.days.iter_mut().enumerate() {
+            for window in windows.iter_mut() {
+                if window.end_time >= window.start_time {
+                    continue;
+                }
+                if window.end_time > NaiveTime::MIN {
+                    carried[(day + 1) % 7].push(TimeWindow {
+                        start_time: NaiveTime::MIN,
+                        ..window.clone()
+                    });
+                }
+                window.end_time = NaiveTime::from_hms_nano_opt(23, 59, 59, 999_999_999).unwrap();
+            }
+        }
+        for (windows, carried) in schedule.days.iter_mut().zip(carried) {
+            windows.extend(carried);
+            windows.sort_by_key(|window| window.start_time);
+        }
+
+        Ok(schedule)
+    }
+}
+
+pub struct Context {
+    /// The sink sounds are played on, or `None` when doing a dry run
+    sink: Option<Sink>,
+    /// Handle to the output stream, used to create extra sinks when sounds may overlap
+    stream_handle: Option<OutputStreamHandle>,
+    /// Extra sinks for sounds started while another one was still playing
+    overlap_sinks: Vec<Sink>,
+    clock: Box<dyn Clock>,
+    /// The config as loaded from file
+    config_file: BaseConfig,
+    /// The config currently in effect, with the active profile applied
+    config: BaseConfig,
+    /// Name of the active profile, or `None` before the first config has been loaded
+    profile: Option<Option<String>>,
+    sleep: Pin<Box<Sleep>>,
+    config_path: PathBuf,
+    /// How long file changes are debounced for
+    debounce: Duration,
+    /// Whether to print events as JSON
+    json: bool,
+    sounds_dir: PathBuf,
+    next_play_path: PathBuf,
+    /// The scheduled play, which is only kept here when `persist_schedule` is off or the next-play file couldn't be written
+    next_play: Option<DateTime<FixedOffset>>,
+    last_play_path: PathBuf,
+    play_count_path: PathBuf,
+    play_times_path: PathBuf,
+    rng_state_path: PathBuf,
+    rng: SpeakerRng,
+    /// When each sound was last played
+    play_times: HashMap<PathBuf, DateTime<FixedOffset>>,
+    /// When the queued sounds are expected to have finished playing, if known
+    playing_until: Option<NaiveDateTime>,
+    /// Paths of the most recently played sounds, oldest first
+    recent: VecDeque<PathBuf>,
+    /// The last `RECENT_PLAYS` plays, newest first, including repeats of the same sound
+    recent_plays: VecDeque<(DateTime<FixedOffset>, PathBuf)>,
+    /// The events last fetched from the calendar
+    calendar: Vec<calendar::Event>,
+    /// Sounds played in the current round of the shuffle bag
+    drawn: HashSet<PathBuf>,
+    /// Sounds collected from the sounds directory, or `None` if they need to be rescanned
+    sounds: Option<Vec<AudioFile>>,
+    /// Kept so that new senders can be handed out to other tasks
+    command_tx: mpsc::Sender<Command>,
+    command_rx: mpsc::Receiver<Command>,
+    paused: bool,
+    /// Factor the master volume is scaled by, as set by the volume command
+    volume: f32,
+    /// Publishes plays to MQTT, if configured
+    mqtt: Option<mqtt::Publisher>,
+    /// Emits plays on D-Bus, if configured
+    dbus: Option<dbus::Publisher>,
+    /// Gains for normalizing sounds, along with when the file was modified when they were measured
+    gains: HashMap<PathBuf, (Option<SystemTime>, f32)>,
+    /// Client for sending webhooks, kept so that connections can be reused
+    webhook_client: reqwest::Client,
+    /// How many times in a row the config has failed to load
+    config_failures: u32,
+    /// When to try loading the config again after it failed to load. Until then, changes to it are not reloaded.
+    reload_at: Option<Instant>,
+    /// Contents of the config file when it was last loaded, so that saving it without changes can be ignored
+    config_contents: Option<String>,
+    /// When to schedule a new play because the scheduled one is further away than `max_lookahead_seconds`
+    reschedule_at: Option<NaiveDateTime>,
+    /// When the context was created, which `startup_delay_seconds` counts from
+    started: DateTime<Utc>,
+}
+
+/// Requests from other tasks for the main loop to do something
+pub enum Command {
+    /// Play a sound right away, without affecting the schedule
+    PlayNow,
+    /// Throw away the scheduled play and schedule a new one
+    Reschedule,
+    /// Reload the config, as if it had changed on disk
+    Reload,
+    /// Stop playing sounds until resumed. The schedule keeps running, but plays are skipped.
+    Pause,
+    /// Start playing sounds again
+    Resume,
+    /// Pause if playing, or resume if paused
+    TogglePause,
+    /// Stop the main loop, fading out any sound that is playing
+    Shutdown,
+    /// Scale the master volume by this factor, on top of `volume_schedule`
+    Volume(f32),
+    /// Reply with the time of the next scheduled play and how long until then
+    NextPlay(oneshot::Sender<Option<(NaiveDateTime, TimeDelta)>>),
+    /// Reply with a snapshot of the current state
+    Status(oneshot::Sender<status::Status>),
+    /// Use these events from the calendar from now on
+    Calendar(Vec<calendar::Event>),
+}
+
+impl std::fmt::Debug for Command {
+    // Written out, so that reply channels aren't dumped into the log
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Command::PlayNow => "PlayNow",
+            Command::Reschedule => "Reschedule",
+            Command::Reload => "Reload",
+            Command::Pause => "Pause",
+            Command::Resume => "Resume",
+            Command::TogglePause => "TogglePause",
+            Command::Shutdown => "Shutdown",
+            Command::Volume(_) => "Volume",
+            Command::NextPlay(_) => "NextPlay",
+            Command::Status(_) => "Status",
+            Command::Calendar(_) => "Calendar",
+        })
+    }
+}
+
+impl Command {
+    /// Parses a command that is given by name in any case, e.g. `"play"` or `"VOLUME 0.5"`. Commands that reply can't be parsed.
+    fn parse(line: &str) -> Option<Command> {
+        let mut words = line.split_whitespace();
+        let name = words.next()?.to_ascii_lowercase();
+        let command = match (name.as_str(), words.next()) {
+            ("play", None) => Command::PlayNow,
+            ("reschedule" | "skip", None) => Command::Reschedule,
+            ("reload", None) => Command::Reload,
+            ("pause", None) => Command::Pause,
+            ("resume", None) => Command::Resume,
+            ("mute", None) => Command::Volume(0.0),
+            ("unmute", None) => Command::Volume(1.0),
+            ("volume", Some(volume)) => Command::Volume(
+                volume
+                    .parse()
+                    .ok()
+                    .filter(|volume: &f32| volume.is_finite() && *volume >= 0.0)?,
+            ),
+            _ => return None,
+        };
+        words.next().is_none().then_some(command)
+    }
+}
+
+/// How many of the most recent plays are included in the status
+const RECENT_PLAYS: usize = 10;
+
+fn assert_send<T: Send>(_: &T) {}
+
+/// Checks at compile time that an application embedding the context can run it on a task of its own,
+/// e.g. with `tokio::spawn(async move { context.run().await })`
+#[allow(dead_code)]
+fn assert_context_is_send(mut context: Context) {
+    assert_send(&context);
+    assert_send(&async move { context.run().await });
+}
+
+impl Context {
+    /// Creates the context, along with a sender that other subsystems can use to send it commands
+    pub fn new(
+        output: Option<(OutputStreamHandle, Sink)>,
+        clock: Box<dyn Clock>,
+        options: Options,
+    ) -> (Self, mpsc::Sender<Command>) {
+        let (command_tx, command_rx) = mpsc::channel(16);
+        let (stream_handle, sink) = output.unzip();
+
+        let context = Context {
+            sink,
+            stream_handle,
+            overlap_sinks: Vec::new(),
+            started: clock.now(),
+            clock,
+            config_file: BaseConfig::default(),
+            config: BaseConfig::default(),
+            profile: None,
+            sleep: Box::pin(tokio::time::sleep(Duration::MAX)),
+            config_path: options.config,
+            debounce: options.debounce,
+            json: options.json,
+            sounds_dir: options.sounds_dir,
+            next_play_path: options.next_play,
+            next_play: None,
+            last_play_path: options.last_play,
+            play_count_path: options.play_count,
+            play_times_path: options.play_times,
+            rng_state_path: options.rng_state,
+            rng: SpeakerRng::default(),
+            play_times: HashMap::new(),
+            playing_until: None,
+            recent: VecDeque::new(),
+            recent_plays: VecDeque::new(),
+            calendar: Vec::new(),
+            drawn: HashSet::new(),
+            sounds: None,
+            command_tx: command_tx.clone(),
+            command_rx,
+            paused: false,
+            volume: 1.0,
+            mqtt: None,
+            dbus: None,
+            gains: HashMap::new(),
+            webhook_client: webhook::client(),
+            config_failures: 0,
+            reload_at: None,
+            config_contents: None,
+            reschedule_at: None,
+        };
+
+        (context, command_tx)
+    }
+
+    /// Runs the schedule until [`Command::Shutdown`] is received
+    pub async fn run(&mut self) {
+        let (mut watcher, mut channel) =
+            async_watcher::AsyncDebouncer::new_with_channel(self.debounce, None)
+                .await
+                .unwrap();
+        // The directory is watched rather than the file itself, as editors that save by replacing the file
+        // would otherwise leave us watching the old, removed file
+        let config_dir = match self.config_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        watcher
+            .watcher()
+            .watch(
+                &config_dir,
+                async_watcher::notify::RecursiveMode::NonRecursive,
+            )
+            .unwrap();
+
+        self.play_times = match std::fs::read_to_string(&self.play_times_path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Invalid play-times file, ignoring it: {e}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        // The calendar is read before the first wake, so that a play that is due during an event isn't played anyway.
+        // Like the other integrations, its source is only taken from the config present at startup.
+        let calendar = BaseConfig::load(&self.config_path)
+            .ok()
+            .and_then(|config| config.calendar);
+        if let Some(calendar) = calendar {
+            self.calendar = calendar::load(&calendar).await.unwrap_or_default();
+            tokio::spawn(calendar::serve(calendar, self.command_tx.clone()));
+        }
+
+        self.wake();
+
+        // The HTTP, metrics, status, MQTT and D-Bus integrations are only started with the config present at startup
+        if let Some(http) = self.config.http.clone() {
+            tokio::spawn(http::serve(http, self.command_tx.clone()));
+        }
+        if let Some(metrics) = self.config.metrics.clone() {
+            tokio::spawn(metrics::serve(metrics));
+        }
+        if let Some(status) = self.config.status.clone() {
+            tokio::spawn(status::serve(status, self.command_tx.clone()));
+        }
+
+        if let Some(mqtt) = self.config.mqtt.clone() {
+            match mqtt::start(mqtt, self.command_tx.clone()) {
+                Ok(publisher) => self.mqtt = Some(publisher),
+                Err(e) => error!("Could not set up MQTT: {e}"),
+            }
+        }
+        if let Some(dbus) = self.config.dbus.clone() {
+            match dbus::start(dbus, self.command_tx.clone()).await {
+                Ok(publisher) => self.dbus = Some(publisher),
+                Err(e) => error!("Could not set up D-Bus: {e}"),
+            }
+        }
+
+        let mut i = 1;
+        let mut watched_sounds_dirs: Vec<PathBuf> = Vec::new();
+
+        // The watchdog is notified from within the loop, so that systemd restarts us if it ever gets stuck
+        let watchdog_interval = systemd::watchdog_interval();
+        let mut watchdog =
+            tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(60 * 60)));
+        systemd::ready();
+
+        // The master volume changes gradually over the day, so keep it up to date
+        let mut volume_update = tokio::time::interval(Duration::from_secs(60));
+
+        // Sleeps run on a monotonic clock, so a jump of the wall clock, e.g. when NTP syncs after booting
+        // without a real-time clock, would otherwise leave us sleeping until the wrong time
+        const CLOCK_JUMP_THRESHOLD: TimeDelta = TimeDelta::seconds(30);
+        let mut clock_check = tokio::time::interval(Duration::from_secs(60));
+        let mut last_check = (Instant::now(), self.clock.now());
+
+        loop {
+            // Sounds are rescanned whenever anything in the sounds directories changes.
+            // Which directories those are can change with the config, so keep watching the current ones.
+            let sounds_dirs = self.sounds_dirs();
+            if watched_sounds_dirs != sounds_dirs {
+                for old in &watched_sounds_dirs {
+                    let _ = watcher.watcher().unwatch(old);
+                }
+                for dir in &sounds_dirs {
+                    if let Err(e) = watcher
+                        .watcher()
+                        .watch(dir, async_watcher::notify::RecursiveMode::Recursive)
+                    {
+                        warn!("Could not watch sounds directory {}: {e}", dir.display());
+                    }
+                }
+                watched_sounds_dirs = sounds_dirs;
+            }
+
+            select! {
+                Some(event) = channel.recv() => {
+                    match event {
+                        Ok(events) => {
+                            let mut config_changed = false;
+                            for event in events {
+                                debug!("{i} -- {event:?}");
+                                i += 1;
+
+                                // Other files next to the config, like the state files, are of no interest
+                                if is_within(&event.path, &self.config_path) {
+                                    config_changed = true;
+                                } else if watched_sounds_dirs.iter().any(|dir| is_within(&event.path, dir)) {
+                                    self.sounds = None;
+                                }
+                            }
+
+                            // A broken config is retried with a backoff, so that it isn't reloaded on every keystroke
+                            if config_changed && self.reload_at.is_none() {
+                                self.config_changed();
+                            }
+                        }
+                        Err(errors) => {
+                            for err in errors {
+                                warn!("File watcher error: {err:?}");
+                            }
+                            self.sounds = None;
+                            self.wake();
+                        }
+                    }
+                }
+                _ = volume_update.tick() => {
+                    self.update_volume();
+                }
+                _ = clock_check.tick() => {
+                    let (instant, wall) = (Instant::now(), self.clock.now());
+                    let expected = TimeDelta::from_std(instant - last_check.0).unwrap_or_default();
+                    let jump = (wall - last_check.1) - expected;
+                    last_check = (instant, wall);
+                    if jump.abs() > CLOCK_JUMP_THRESHOLD {
+                        info!("Wall clock jumped by {} seconds, checking the next play again", jump.num_seconds());
+                        self.wake();
+                    }
+                }
+                _ = watchdog.tick(), if watchdog_interval.is_some() => {
+                    systemd::watchdog();
+                }
+                _ = tokio::time::sleep_until(self.reload_at.unwrap_or_else(Instant::now)), if self.reload_at.is_some() => {
+                    self.reload_at = None;
+                    self.wake();
+                }
+                _ = &mut self.sleep => {
+                    // We should have now waited until the next play time
+                    self.wake();
+                }
+                Some(command) = self.command_rx.recv() => {
+                    // Shutting down ends the loop, so it can't be handled with the other commands
+                    if let Command::Shutdown = command {
+                        info!("Shutting down");
+                        break;
+                    }
+                    self.handle_command(command);
+                }
+                else => break
+            }
+        }
+
+        // The next play is written to file as soon as it is scheduled, so there is no state left to save
+        self.fade_out().await;
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        info!("Received command {command:?}");
+        if self.profile.is_none() && matches!(command, Command::PlayNow | Command::Reschedule) {
+            warn!("No valid config has been loaded, ignoring command");
+            return;
+        }
+        match command {
+            Command::PlayNow => self.play_sound(PlayReason::Manual),
+            Command::Reschedule => self.schedule_new_play(),
+            Command::Reload => {
+                self.sounds = None;
+                self.wake();
+            }
+            Command::Pause => {
+                self.paused = true;
+                for sink in self.sinks() {
+                    sink.pause();
+                }
+            }
+            Command::Volume(volume) => {
+                info!("Setting volume to {volume}");
+                self.volume = volume;
+                self.update_volume();
+            }
+            Command::Resume => {
+                self.paused = false;
+                for sink in self.sinks() {
+                    sink.play();
+                }
+                // Plays that came up while paused have already been skipped and rescheduled,
+                // so this only picks up where the schedule currently is
+                self.wake();
+            }
+            Command::TogglePause => {
+                let command = if self.paused {
+                    Command::Resume
+                } else {
+                    Command::Pause
+                };
+                self.handle_command(command);
+            }
+            // Handled by `run`, which owns the loop
+            Command::Shutdown => {}
+            Command::NextPlay(reply) => {
+                let next_play = self
+                    .read_next_play()
+                    .ok()
+                    .map(|time| (time, self.until(time)));
+                // The asker may have given up waiting, which is fine
+                let _ = reply.send(next_play);
+            }
+            Command::Status(reply) => {
+                let next_play = self.read_next_play().ok();
+                let recent_plays = self
+                    .recent_plays
+                    .iter()
+                    .map(|(time, path)| status::Play {
+                        path: path.clone(),
+                        time: *time,
+                    })
+                    .collect();
+                let status = status::Status {
+                    profile: self.profile.clone().flatten(),
+                    next_play: next_play.map(|time| self.localize(time)),
+                    seconds_remaining: next_play.map(|time| self.until(time).num_seconds()),
+                    last_played: self
+                        .play_times
+                        .iter()
+                        .max_by_key(|(_, time)| **time)
+                        .map(|(path, _)| path.clone()),
+                    paused: self.paused,
+                    volume: self.volume,
+                    plays_today: self.plays_on(self.play_day()),
+                    windows: self
+                        .config
+                        .schedule
+                        .windows(self.now().date())
+                        .iter()
+                        .map(|window| status::Window {
+                            start: window.start_time,
+                            end: window.end_time,
+                        })
+                        .collect(),
+                    recent_plays,
+                };
+                let _ = reply.send(status);
+            }
+            Command::Calendar(events) => {
+                if events != self.calendar {
+                    info!("Calendar changed, now with {} events", events.len());
+                    self.calendar = events;
+                    // A play that is now during an event is skipped when it comes up, like any other invalid time
+                    self.apply_calendar();
+                }
+            }
+        }
+    }
+
+    /// Gives the schedule currently in effect the events from the calendar
+    fn apply_calendar(&mut self) {
+        let Some(calendar) = &self.config.calendar else {
+            return;
+        };
+        let to_local = |time: calendar::EventTime| match time {
+            calendar::EventTime::Utc(time) => self.to_local(time.fixed_offset()),
+            calendar::EventTime::Local(time) => time,
+        };
+        let events = self
+            .calendar
+            .iter()
+            .map(|event| (to_local(event.start), to_local(event.end)))
+            .collect();
+        self.config.schedule.set_calendar(calendar.mode, events);
+    }
+
+    /// Fades out whatever is currently playing and stops the sink, so that shutting down doesn't cut a sound off abruptly
+    async fn fade_out(&self) {
+        const STEPS: u32 = 20;
+        const FADE_DURATION: Duration = Duration::from_millis(500);
+
+        let sinks: Vec<_> = self
+            .sinks()
+            .filter(|sink| !sink.empty())
+            .map(|sink| (sink, sink.volume()))
+            .collect();
+        if sinks.is_empty() {
+            return;
+        }
+
+        for step in (0..STEPS).rev() {
+            for (sink, volume) in &sinks {
+                sink.set_volume(volume * step as f32 / STEPS as f32);
+            }
+            tokio::time::sleep(FADE_DURATION / STEPS).await;
+        }
+        for (sink, _) in &sinks {
+            sink.stop();
+        }
+    }
+
+    /// Replaces the main sink with a new one, fading out whatever is playing on the old one over `crossfade_ms`
+    fn crossfade_out(&mut self) {
+        self.playing_until = None;
+        let (Some(handle), Some(old)) = (&self.stream_handle, &self.sink) else {
+            return;
+        };
+        let sink = match Sink::try_new(handle) {
+            Ok(sink) => sink,
+            Err(e) => {
+                warn!("Could not create sink to crossfade to, stopping the previous sound instead: {e}");
+                // Clearing the sink also pauses it
+                old.clear();
+                old.play();
+                return;
+            }
+        };
+        sink.set_volume(self.master_volume());
+
+        let Some(old) = self.sink.replace(sink) else {
+            return;
+        };
+        let duration = Duration::from_millis(self.config.general.crossfade_ms);
+        tokio::spawn(async move {
+            const STEPS: u32 = 20;
+
+            let volume = old.volume();
+            for step in (0..STEPS).rev() {
+                old.set_volume(volume * step as f32 / STEPS as f32);
+                tokio::time::sleep(duration / STEPS).await;
+            }
+            old.stop();
+        });
+    }
+
+    /// Switches to a seeded generator or back to an unseeded one, if `seed` has changed
+    fn update_rng(&mut self) {
+        let seed = self.config.general.seed;
+        if seed == self.rng.seed() {
+            return;
+        }
+
+        self.rng = match seed {
+            Some(seed) => {
+                // Continue where the sequence left off, unless it was for another seed
+                let position = std::fs::read_to_string(&self.rng_state_path)
+                    .ok()
+                    .and_then(|state| SpeakerRng::parse_state(&state))
+                    .filter(|(saved_seed, _)| *saved_seed == seed)
+                    .map(|(_, position)| position);
+                info!("Using seed {seed}");
+                SpeakerRng::seeded(seed, position)
+            }
+            None => SpeakerRng::default(),
+        };
+    }
+
+    /// Saves the position of the seeded generator, so that a restart continues from there
+    fn save_rng(&self) {
+        if let Some(state) = self.rng.state() {
+            if let Err(e) = write_atomic(&self.rng_state_path, state) {
+                warn!("Could not write rng-state file: {e}");
+            }
+        }
+    }
+
+    /// Master volume right now, according to `volume_schedule` and the volume command
+    fn master_volume(&self) -> f32 {
+        self.config.general.volume_at(self.now().time()) * self.volume
+    }
+
+    /// Sets the master volume of all sinks according to `volume_schedule` and the volume command
+    fn update_volume(&self) {
+        let volume = self.master_volume();
+        for sink in self.sinks() {
+            sink.set_volume(volume);
+        }
+    }
+
+    /// Whether a sound is still playing on the main sink
+    fn is_playing(&self) -> bool {
+        match &self.sink {
+            Some(sink) => !sink.empty(),
+            // Without a sink, go by how long the sound is
+            None => self.playing_until.is_some_and(|end| end > self.now()),
+        }
+    }
+
+    /// All sinks that may currently be playing
+    fn sinks(&self) -> impl Iterator<Item = &Sink> {
+        self.sink.iter().chain(&self.overlap_sinks)
+    }
+
+    /// Returns the sink a new sound should be played on.
+    ///
+    /// Without `allow_overlap`, this is always the main sink, so sounds are queued after each other.
+    /// With it, a new sink is created whenever the main sink is busy, so the sounds are mixed.
+    fn free_sink(&mut self) -> Option<&Sink> {
+        // Finished sinks are cleaned up here, as this is the only place new ones are created
+        self.overlap_sinks.retain(|sink| !sink.empty());
+
+        let sink = self.sink.as_ref()?;
+        if !self.config.general.allow_overlap || sink.empty() {
+            return self.sink.as_ref();
+        }
+
+        let handle = self.stream_handle.as_ref()?;
+        match Sink::try_new(handle) {
+            Ok(sink) => {
+                sink.set_volume(self.master_volume());
+                self.overlap_sinks.push(sink);
+                self.overlap_sinks.last()
+            }
+            Err(e) => {
+                warn!("Could not create sink for overlapping sound, queueing it instead: {e}");
+                self.sink.as_ref()
+            }
+        }
+    }
+
+    /// Reloads the config after the file has changed, unless its contents are still the same.
+    /// Editors may save several times in a row, and there is no need to wake up for each of them.
+    fn config_changed(&mut self) {
+        let contents = std::fs::read_to_string(&self.config_path).ok();
+        if contents.is_some() && contents == self.config_contents {
+            debug!("Config file unchanged, not reloading");
+            return;
+        }
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        // Update config from file
+        let contents = std::fs::read_to_string(&self.config_path);
+        match contents
+            .as_ref()
+            .map_err(|e| anyhow::anyhow!("{e}"))
+            .and_then(|contents| BaseConfig::parse(contents))
+        {
+            Ok(config) => {
+                self.config_file = config;
+                self.config_contents = contents.ok();
+                self.config_failures = 0;
+                self.reload_at = None;
+                metrics::record_reload();
+            }
+            Err(e) => {
+                // Wait longer after each failure in a row, up to a limit
+                const MIN_BACKOFF: Duration = Duration::from_secs(1);
+                const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+                let backoff = MIN_BACKOFF
+                    .saturating_mul(2u32.saturating_pow(self.config_failures))
+                    .min(MAX_BACKOFF);
+                self.config_failures += 1;
+                self.reload_at = Some(Instant::now() + backoff);
+
+                if self.profile.is_none() {
+                    // There is no previous valid config to fall back to, so don't play anything until there is one
+                    error!(
+                        "Error reading config, idling until it is fixed. Trying again in {} seconds: {e}",
+                        backoff.as_secs()
+                    );
+                    self.sleep = Box::pin(tokio::time::sleep(Duration::MAX));
+                    return;
+                }
+                error!(
+                    "Error reading config, keeping previous config. Trying again in {} seconds: {e}",
+                    backoff.as_secs()
+                );
+            }
+        }
+
+        // Use the profile for today, which may have changed since the last wake
+        let (config, profile) = self.config_file.for_date(self.today());
+        let previous_sounds_dirs = self.weighted_sounds_dirs();
+        // Stored times carry their offset, so they are still read correctly after switching
+        if self.profile.is_some() && config.general.timezone != self.config.general.timezone {
+            info!(
+                "Switching to timezone {}",
+                config
+                    .general
+                    .timezone
+                    .map_or("of the system".to_owned(), |tz| tz.to_string())
+            );
+        }
+        let filter_changed = config.general.exclude != self.config.general.exclude
+            || config.general.include != self.config.general.include
+            || config.general.max_duration_seconds != self.config.general.max_duration_seconds
+            || config.general.max_file_bytes != self.config.general.max_file_bytes;
+        self.config = config;
+        self.apply_calendar();
+        if self.weighted_sounds_dirs() != previous_sounds_dirs || filter_changed {
+            self.sounds = None;
+        }
+        let profile_changed = self
+            .profile
+            .as_ref()
+            .is_some_and(|previous| *previous != profile);
+        if profile_changed {
+            info!(
+                "Switching to profile {}",
+                profile.as_deref().unwrap_or("default")
+            );
+        }
+        self.profile = Some(profile);
+
+        debug!("{:#?}", self.config);
+
+        self.update_volume();
+        self.update_rng();
+
+        // Check if we are waiting for a play event
+        match self.read_next_play() {
+            Ok(next_play) => {
+                let diff = self.until(next_play);
+                debug!("diff: {diff}");
+                if diff < TimeDelta::zero() {
+                    info!("Next play time reached {:.2} seconds ago", diff.abs());
+                    // We should play sound and then schedule a new next-play
+                    let stale = self
+                        .config
+                        .general
+                        .stale_play_grace_seconds
+                        .is_some_and(|grace| -diff > TimeDelta::seconds(grace as i64));
+                    if stale {
+                        info!("Next play is older than stale_play_grace_seconds, skip it and reschedule");
+                        self.emit(Event::Skipped { reason: "stale" });
+                        self.schedule_new_play();
+                    } else if self.now() < self.startup_delay_end() {
+                        // The play is kept, and played once the delay is over if the time is still valid then
+                        let end = self.startup_delay_end();
+                        info!("Still within startup_delay_seconds, waiting until {end} to play");
+                        self.reset_sleep(end - self.now());
+                    }
+                    // The daily limit also applies to fixed times
+                    else if self.daily_limit_reached() {
+                        info!("Maximum plays for today reached, reschedule");
+                        self.emit(Event::Skipped {
+                            reason: "daily_limit",
+                        });
+                        self.schedule_new_play();
+                    }
+                    // Fixed times are played even outside the windows
+                    else if self.config.schedule.is_fixed_time(next_play) {
+                        info!("Fixed time reached, play sound and reschedule");
+                        self.play_sound(PlayReason::Fixed);
+                        self.schedule_new_play();
+                    } else if let Some(end) = self.min_gap_end() {
+                        // E.g. a play that was due while the config was broken, right after a manual play
+                        info!("Last play was less than min_gap_seconds ago, waiting until {end} to play");
+                        self.reset_sleep(self.until(end));
+                    } else if self.config.schedule.is_time_valid(self.now()) {
+                        info!("Play sound and reschedule");
+                        self.play_sound(PlayReason::Scheduled);
+                        self.schedule_new_play();
+                    } else {
+                        info!("Current time invalid, reschedule");
+                        self.emit(Event::Skipped {
+                            reason: "invalid_time",
+                        });
+                        // Current time is not valid
+                        // Possible causes:
+                        // 1. We waited too long, and we just barely entered invalid time
+                        // 2. We have been turned off and just started, within invalid time
+                        // The easiest solution is to simply reschedule, as these should be pretty unusual circumstances
+                        self.schedule_new_play();
+                    }
+                } else if let Some(fixed) = self
+                    .config
+                    .schedule
+                    .next_fixed_time(self.now())
+                    .filter(|fixed| *fixed < next_play)
+                {
+                    // The fixed times were changed since the next play was scheduled
+                    info!("Fixed time @ {fixed} comes before next play, reschedule");
+                    self.schedule_new_play();
+                } else if profile_changed {
+                    // The next play was scheduled with the settings of the previous profile
+                    info!("Profile changed, reschedule");
+                    self.schedule_new_play();
+                } else if self.reschedule_at.is_some_and(|at| self.now() >= at) {
+                    info!("Next play is still further away than max_lookahead_seconds, reschedule");
+                    self.schedule_new_play();
+                } else {
+                    // After a restart, the next play may have been scheduled before
+                    if self.reschedule_at.is_none() {
+                        self.reschedule_at = self.lookahead_horizon(next_play);
+                    }
+                    info!(
+                        "Next play time not reached, waiting additional {} seconds",
+                        diff.num_seconds()
+                    );
+                    self.emit(Event::Waiting {
+                        next_play: self.localize(next_play),
+                    });
+                    // We should simply wait
+                    self.reset_sleep(diff);
+                }
+            }
+            Err(_) => {
+                // Something went wrong
+                // We could either not read a next-play file, it is invalid, or nothing is scheduled in memory yet
+                // We should schedule a new next-play
+                info!("Could not find and/or read next play, reschedule");
+                self.schedule_new_play();
+            }
+        }
+    }
+
+    /// Returns the sounds in the sounds directories, scanning them if they have changed since last time
+    fn sounds(&mut self) -> &[AudioFile] {
+        if self.sounds.is_none() {
+            let dirs = self.weighted_sounds_dirs();
+            let mut roots: Vec<_> = dirs
+                .iter()
+                .map(|(dir, weight)| {
+                    debug!("Scanning {}", dir.display());
+                    (*weight, self.collect_sounds(dir))
+                })
+                .filter(|(_, sounds)| !sounds.is_empty())
+                .collect();
+
+            // Like subdirectories, each directory with sounds gets a share of the weight, by default the same
+            let total: f32 = roots.iter().map(|(weight, _)| weight).sum();
+            if total <= 0.0 && !roots.is_empty() {
+                warn!("Sound directories have no weight");
+                roots.clear();
+            }
+            for (weight, sounds) in &mut roots {
+                for sound in sounds {
+                    sound.config.weight *= *weight / total;
+                }
+            }
+            let roots: Vec<_> = roots.into_iter().map(|(_, sounds)| sounds).collect();
+            let sounds: Vec<_> = roots.into_iter().flatten().collect();
+            warn_mixed_formats(&sounds);
+            self.sounds = Some(sounds);
+        }
+        self.sounds.as_deref().unwrap_or_default()
+    }
+
+    /// The directories sounds are currently picked from
+    fn sounds_dirs(&self) -> Vec<PathBuf> {
+        self.weighted_sounds_dirs()
+            .into_iter()
+            .map(|(dir, _)| dir)
+            .collect()
+    }
+
+    /// The directories sounds are currently picked from, with their weights
+    fn weighted_sounds_dirs(&self) -> Vec<(PathBuf, f32)> {
+        let general = &self.config.general;
+        if !general.sound_dirs.is_empty() {
+            return general
+                .sound_dirs
+                .iter()
+                .map(|dir| (dir.path().to_path_buf(), dir.weight()))
+                .collect();
+        }
+        vec![(
+            general
+                .sounds_dir
+                .clone()
+                .unwrap_or_else(|| self.sounds_dir.clone()),
+            default_weight(),
+        )]
+    }
+
+    /// Collects all sounds in the given directory, recursively.
+    ///
+    /// Every directory has a total weight of 1, which is split between its entries: each file takes
+    /// its own configured weight, and each subdirectory containing sounds takes the weight in its `config.toml`,
+    /// which is then split between its own entries in the same way. The returned weights therefore sum to 1.
+    fn collect_sounds(&self, path: impl AsRef<Path>) -> Vec<AudioFile> {
+        let path = path.as_ref();
+        let filter = SoundFilter::new(&self.config.general);
+        let dir_config = DirectoryConfig::load_or_default(path);
+        self.collect_sounds_inheriting(path, &dir_config, &FileSettings::default(), &filter)
+    }
+
+    /// Collects sounds like [`Self::collect_sounds`], with the settings inherited from the parent directories
+    fn collect_sounds_inheriting(
+        &self,
+        path: &Path,
+        dir_config: &DirectoryConfig,
+        inherited: &FileSettings,
+        filter: &SoundFilter,
+    ) -> Vec<AudioFile> {
+        if !dir_config.enabled {
+            debug!("Skipping disabled directory {}", path.display());
+            return Vec::new();
+        }
+        let inherited = dir_config.defaults().or(inherited);
+
+        let mut res = vec![];
+        // Each entry is a group of files sharing a portion of the directory's weight:
+        // the weight of the group, and the files with weights relative to the group
+        let mut groups: Vec<(f32, Vec<AudioFile>)> = vec![];
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read sounds directory {}: {e}", path.display());
+                return res;
+            }
+        };
+        for file in entries {
+            let file = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Could not read entry in {}: {e}", path.display());
+                    continue;
+                }
+            };
+
+            let file_type = match file.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    warn!("Could not get file type of {}: {e}", file.path().display());
+                    continue;
+                }
+            };
+            let name = file.file_name();
+            let name = name.to_string_lossy();
+            if file_type.is_file() {
+                if !filter.allows_file(&name) {
+                    debug!("Skipping excluded file {}", file.path().display());
+                    continue;
+                }
+                if !is_audio_file(&file.path()) {
+                    debug!("Skipping non-audio file {}", file.path().display());
+                    continue;
+                }
+                if let Some(reason) = filter.exceeds_limits(&file.path()) {
+                    warn!("Skipping {}: {reason}", file.path().display());
+                    continue;
+                }
+
+                let listed = file
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| dir_config.files.get(name))
+                    .cloned()
+                    .unwrap_or_default();
+                let sidecar = FileSettings::load_sidecar(&file.path()).unwrap_or_else(|e| {
+                    warn!("Error reading settings for {}: {e}", file.path().display());
+                    FileSettings::default()
+                });
+                let settings = sidecar.or(&listed).or(&inherited);
+                let config = settings.resolve();
+
+                let weight = config.weight;
+                groups.push((
+                    weight,
+                    vec![AudioFile {
+                        path: file.path(),
+                        config: FileConfig {
+                            weight: 1.0,
+                            ..config
+                        },
+                        tags: settings.tags,
+                        folder_weight: dir_config.folder_weight,
+                    }],
+                ));
+            } else if file_type.is_dir() {
+                if !dir_config.recursive {
+                    debug!(
+                        "Skipping subdirectory {} of non-recursive directory",
+                        file.path().display()
+                    );
+                    continue;
+                }
+                if !filter.allows_dir(&name) {
+                    debug!("Skipping excluded directory {}", file.path().display());
+                    continue;
+                }
+                let sub_config = DirectoryConfig::load_or_default(&file.path());
+                let sounds =
+                    self.collect_sounds_inheriting(&file.path(), &sub_config, &inherited, filter);
+                // Directories without sounds should not take weight away from the others
+                if !sounds.is_empty() {
+                    groups.push((sub_config.weight, sounds));
+                }
+            }
+        }
+
+        let total: f32 = groups.iter().map(|(weight, _)| weight).sum();
+        if total <= 0.0 {
+            if !groups.is_empty() {
+                warn!("Sounds in {} have no weight", path.display());
+            }
+            return res;
+        }
+
+        for (weight, mut files) in groups {
+            for file in &mut files {
+                file.config.weight *= weight / total;
+            }
+            res.append(&mut files);
+        }
+
+        for file in &mut res {
+            // Files in subdirectories already have their own directory's volume applied,
+            // so this multiplies the volumes of all directories down the tree
+            file.config.volume *= dir_config.volume;
+            // Files are tagged with the tags of every directory they are in
+            file.tags.extend(dir_config.tags.iter().cloned());
+        }
+
+        res
+    }
+
+    pub fn play_sound(&mut self, reason: PlayReason) {
+        if self.paused {
+            info!("Paused, skipping play");
+            self.emit(Event::Skipped { reason: "paused" });
+            return;
+        }
+
+        let crossfade = !self.config.general.allow_overlap
+            && self.config.general.crossfade_ms > 0
+            && self.is_playing();
+        if crossfade {
+            info!("Previous sound still playing, crossfading into the next one");
+            self.crossfade_out();
+        } else if !self.config.general.allow_overlap && self.is_playing() {
+            match self.config.general.overrun_policy {
+                OverrunPolicy::Queue => {}
+                OverrunPolicy::Truncate => {
+                    info!("Previous sound still playing, stopping it");
+                    if let Some(sink) = &self.sink {
+                        // Clearing the sink also pauses it
+                        sink.clear();
+                        sink.play();
+                    }
+                    self.playing_until = None;
+                }
+                OverrunPolicy::Skip => {
+                    info!("Previous sound still playing, skipping play");
+                    self.emit(Event::Skipped { reason: "overrun" });
+                    return;
+                }
+            }
+        }
+
+        let window_dir = self
+            .config
+            .schedule
+            .active_window(self.now())
+            .and_then(|window| window.sounds_dir.clone());
+        let sounds = match &self.config.schedule.fixed_sounds_dir {
+            Some(dir) if reason == PlayReason::Fixed => self.collect_sounds(dir),
+            _ => {
+                // Only the global sounds directory is cached, as the others are only used during their window
+                let sounds = match window_dir {
+                    Some(dir) => self.collect_sounds(dir),
+                    None => self.sounds().to_vec(),
+                };
+                self.filter_by_tags(sounds)
+            }
+        };
+        let now = self.now();
+        let sounds: Vec<_> = sounds
+            .into_iter()
+            .filter(|sound| sound.config.allowed_at(now))
+            .collect();
+
+        // Fixed times play a single sound, as they are meant to be predictable
+        let count = match &self.config.burst {
+            Some(burst) if reason != PlayReason::Fixed && self.rng.gen_bool(burst.probability) => {
+                let count = self.rng.gen_range(burst.min_count..=burst.max_count);
+                info!("Playing a burst of {count} sounds");
+                count
+            }
+            _ => 1,
+        };
+        // Each sound is queued after the previous one, and counts as played before the next is picked,
+        // so that no-repeat and cooldowns apply within the burst as well
+        for i in 0..count {
+            if !self.play_one(&sounds, reason, crossfade && i == 0) {
+                break;
+            }
+        }
+    }
+
+    /// Picks one of `sounds` and plays it, returning whether there was anything to play.
+    /// With `crossfade`, the sound fades in over `crossfade_ms`.
+    fn play_one(&mut self, sounds: &[AudioFile], reason: PlayReason, crossfade: bool) -> bool {
+        let candidates = self.exclude_recent(sounds);
+        let candidates = self.exclude_cooling_down(candidates);
+        let mut candidates = self.exclude_drawn(candidates);
+        // If every candidate was just played, there is nothing to favor, so fall back to the configured weights
+        let anti_recency = self.config.general.selection_mode == SelectionMode::AntiRecency
+            && candidates
+                .iter()
+                .any(|file| self.recency_factor(file) > 0.0);
+
+        // The weights are calculated from the context, so the generator can't be borrowed from it while picking
+        let mut rng = std::mem::take(&mut self.rng);
+        // Keep picking until we find a sound that can actually be decoded
+        let picked = loop {
+            let sound = if self.config.general.selection_mode == SelectionMode::Folder {
+                pick_by_folder(&candidates, &mut rng)
+            } else {
+                candidates
+                    .choose_weighted(&mut rng, |file| {
+                        if anti_recency {
+                            file.config.weight * self.recency_factor(file)
+                        } else {
+                            file.config.weight
+                        }
+                    })
+                    .ok()
+                    .copied()
+            };
+            let Some(sound) = sound else {
+                break None;
+            };
+
+            // Without audio output there is nothing to decode for
+            if self.sink.is_none() {
+                break Some((sound, None));
+            }
+
+            match open_sound(&sound.path) {
+                Ok(decoder) => break Some((sound, Some(decoder))),
+                Err(e) => {
+                    warn!(
+                        "Could not play {}, trying another: {e}",
+                        sound.path.display()
+                    );
+                    candidates.retain(|file| file.path != sound.path);
+                }
+            }
+        };
+        self.rng = rng;
+        self.save_rng();
+
+        let Some((sound, decoder)) = picked else {
+            warn!("No sound to play");
+            return false;
+        };
+
+        let name = sound
+            .path
+            .file_name()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or("-- CANNOT GET FILE NAME --".into());
+        match decoder {
+            Some(decoder) => {
+                let gain = if self.config.general.normalize {
+                    self.normalization_gain(&sound.path)
+                } else {
+                    1.0
+                };
+                let min_fade_in = if crossfade {
+                    self.config.general.crossfade_ms
+                } else {
+                    0
+                };
+                debug!(
+                    "{name} is {} Hz with {} channels",
+                    decoder.sample_rate(),
+                    decoder.channels()
+                );
+                // Every source on a sink is converted to the sample rate and channels of the output stream by the mixer,
+                // so sounds of different formats can be queued after each other
+                let speed = self.random_speed(sound);
+                let source = self.build_source(sound, decoder, gain, min_fade_in, speed);
+                let announcement = if self.config.general.announce {
+                    self.announcement(sound)
+                } else {
+                    None
+                };
+                // Queued sounds start once the ones before them have ended
+                let start = self
+                    .playing_until
+                    .filter(|end| *end > self.now() && !self.config.general.allow_overlap)
+                    .unwrap_or(self.now());
+                let announcement_duration = match &announcement {
+                    Some(announcement) => announcement.total_duration(),
+                    None => Some(Duration::ZERO),
+                };
+                self.playing_until = source
+                    .total_duration()
+                    .zip(announcement_duration)
+                    .and_then(|(sound, announcement)| {
+                        TimeDelta::from_std(sound + announcement).ok()
+                    })
+                    .map(|duration| start + duration);
+                if let Some(sink) = self.free_sink() {
+                    // Appended to the same sink, so that the sound plays right after it has been announced
+                    if let Some(announcement) = announcement {
+                        sink.append(announcement);
+                    }
+                    sink.append(source);
+                }
+                info!("Playing {name} ({reason})");
+            }
+            None => info!("Would play {name} ({reason})"),
+        }
+        self.emit(Event::Played {
+            file: &sound.path,
+            reason,
+        });
+
+        // Remember when we last played, so that the next play can keep its distance
+        if let Err(e) = self.write_time(&self.last_play_path, self.now()) {
+            warn!("Could not write last-play file: {e}");
+        }
+
+        metrics::record_play(self.localize(self.now()));
+
+        let today = self.play_day();
+        let count = self.plays_on(today) + 1;
+        if let Err(e) = write_atomic(&self.play_count_path, format!("{today} {count}\n")) {
+            warn!("Could not write play-count file: {e}");
+        }
+
+        let entry = HistoryEntry {
+            time: self.localize(self.now()),
+            path: sound.path.clone(),
+            reason,
+        };
+        self.recent_plays
+            .push_front((entry.time, sound.path.clone()));
+        self.recent_plays.truncate(RECENT_PLAYS);
+        if let Some(history_file) = &self.config.general.history_file {
+            if let Err(e) = append_history(history_file, &entry) {
+                warn!("Could not write to history file: {e}");
+            }
+        }
+        if let Some(mqtt) = &self.mqtt {
+            match serde_json::to_string(&entry) {
+                Ok(payload) => mqtt.played(payload),
+                Err(e) => warn!("Could not serialize play for MQTT: {e}"),
+            }
+        }
+        if let Some(dbus) = &self.dbus {
+            dbus.played(&sound.path, entry.time);
+        }
+        if let Some(config) = &self.config.webhook {
+            let play = webhook::Play {
+                time: entry.time,
+                path: sound.path.clone(),
+                weight: sound.config.weight,
+                reason,
+            };
+            webhook::send(&self.webhook_client, config, play);
+        }
+
+        self.play_times
+            .insert(sound.path.clone(), self.localize(self.now()));
+        match serde_json::to_string(&self.play_times) {
+            Ok(contents) => {
+                if let Err(e) = write_atomic(&self.play_times_path, contents) {
+                    warn!("Could not write play-times file: {e}");
+                }
+            }
+            Err(e) => warn!("Could not serialize play times: {e}"),
+        }
+
+        self.recent.push_back(sound.path.clone());
+        if self.config.general.selection_mode == SelectionMode::ShuffleBag {
+            self.drawn.insert(sound.path.clone());
+        }
+        while self.recent.len() > self.config.general.no_repeat_history.max(1) {
+            self.recent.pop_front();
+        }
+
+        true
+    }
+
+    /// Returns the gain that brings a sound to the target loudness.
+    /// Measuring requires decoding the whole sound, so the gain is cached until the file changes.
+    fn normalization_gain(&mut self, path: &Path) -> f32 {
+        /// Quiet sounds are not amplified more than this, so that near silence isn't blown up into noise
+        const MAX_GAIN: f32 = 10.0;
+
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if let Some((cached_modified, gain)) = self.gains.get(path) {
+            if modified.is_some() && *cached_modified == modified {
+                return *gain;
+            }
+        }
+
+        let gain = match measure_loudness(path) {
+            Ok(Loudness { rms, peak }) if rms > 0.0 => {
+                // Amplifying past the peak would clip
+                let gain = (self.config.general.normalize_target / rms)
+                    .min(MAX_GAIN)
+                    .min(1.0 / peak);
+                debug!(
+                    "Measured {}: RMS {rms:.3}, peak {peak:.3}, gain {gain:.2}",
+                    path.display()
+                );
+                gain
+            }
+            Ok(_) => 1.0,
+            Err(e) => {
+                warn!("Could not measure loudness of {}: {e}", path.display());
+                1.0
+            }
+        };
+        self.gains.insert(path.to_path_buf(), (modified, gain));
+        gain
+    }
+
+    /// Speaks the title of the sound with `announce_command`, or `None` if that fails
+    fn announcement(&self, sound: &AudioFile) -> Option<Decoder<std::io::Cursor<Vec<u8>>>> {
+        let text = match &sound.config.title {
+            Some(title) => title.clone(),
+            None => sound.path.file_stem()?.to_string_lossy().into_owned(),
+        };
+        let Some((program, args)) = self.config.general.announce_command.split_first() else {
+            warn!("No announce command configured, skipping announcement");
+            return None;
+        };
+
+        let output = match std::process::Command::new(program)
+            .args(args)
+            .arg(&text)
+            .stderr(std::process::Stdio::null())
+            .output()
+        {
+            Ok(output) if output.status.success() => output.stdout,
+            Ok(output) => {
+                warn!(
+                    "Announce command failed with {}, skipping announcement",
+                    output.status
+                );
+                return None;
+            }
+            Err(e) => {
+                warn!("Could not run announce command {program}, skipping announcement: {e}");
+                return None;
+            }
+        };
+        match Decoder::new(std::io::Cursor::new(output)) {
+            Ok(decoder) => {
+                debug!("Announcing {text:?}");
+                Some(decoder)
+            }
+            Err(e) => {
+                warn!("Could not decode announcement, skipping it: {e}");
+                None
+            }
+        }
+    }
+
+    /// Applies the configured volume and fades to a decoded sound
+    fn build_source(
+        &self,
+        sound: &AudioFile,
+        decoder: Decoder<BufReader<std::fs::File>>,
+        gain: f32,
+        min_fade_in_ms: u64,
+        speed: f32,
+    ) -> Box<dyn Source<Item = i16> + Send> {
+        let mut source: Box<dyn Source<Item = i16> + Send> =
+            Box::new(decoder.amplify(gain * sound.config.volume));
+        // Changing the speed changes the duration, so this is done before fading out at the end
+        if speed != 1.0 {
+            source = Box::new(source.speed(speed));
+        }
+
+        let fade_in = sound
+            .config
+            .fade_in_ms
+            .unwrap_or(self.config.general.fade_in_ms)
+            .max(min_fade_in_ms);
+        if fade_in > 0 {
+            source = Box::new(source.fade_in(Duration::from_millis(fade_in)));
+        }
+
+        let fade_out = sound
+            .config
+            .fade_out_ms
+            .unwrap_or(self.config.general.fade_out_ms);
+        if fade_out > 0 {
+            match source.total_duration() {
+                Some(total) => {
+                    source = Box::new(fade_out_at_end(
+                        source,
+                        total,
+                        Duration::from_millis(fade_out),
+                    ))
+                }
+                None => debug!("Cannot get duration of sound, skipping fade-out"),
+            }
+        }
+
+        source
+    }
+
+    /// Picks the speed to play a sound at according to `speed_variation`
+    fn random_speed(&mut self, sound: &AudioFile) -> f32 {
+        const MAX_VARIATION: f32 = 0.5;
+
+        let variation = self
+            .config
+            .general
+            .speed_variation
+            .clamp(0.0, MAX_VARIATION);
+        if variation <= 0.0 || !sound.config.vary_speed {
+            return 1.0;
+        }
+        let speed = 1.0 + self.rng.gen_range(-variation..=variation);
+        self.save_rng();
+        debug!("Playing at {:.0}% speed", speed * 100.0);
+        speed
+    }
+
+    /// Keeps only the sounds whose tags are eligible in the currently active window
+    fn filter_by_tags(&self, mut sounds: Vec<AudioFile>) -> Vec<AudioFile> {
+        let Some(window) = self.config.schedule.active_window(self.now()) else {
+            // Sounds played outside of the schedule, e.g. manually, can be anything
+            return sounds;
+        };
+        if window.tags.is_empty() {
+            return sounds;
+        }
+
+        sounds.retain(|file| file.tags.iter().any(|tag| window.tags.contains(tag)));
+        sounds
+    }
+
+    /// How much of its configured weight a sound has recovered since it was last played, from 0 to 1
+    fn recency_factor(&self, file: &AudioFile) -> f32 {
+        let Some(last_play) = self.play_times.get(&file.path) else {
+            return 1.0;
+        };
+        let recovery = self.config.general.recovery_seconds as f32;
+        if recovery <= 0.0 {
+            return 1.0;
+        }
+        let elapsed = (self.clock.now() - last_play.to_utc()).num_seconds() as f32;
+        (elapsed / recovery).clamp(0.0, 1.0)
+    }
+
+    /// Removes the sounds that were played within their cooldown from the candidates.
+    /// If that would leave nothing to play, only the sound played longest ago is kept.
+    fn exclude_cooling_down<'a>(&self, candidates: Vec<&'a AudioFile>) -> Vec<&'a AudioFile> {
+        let now = self.clock.now();
+        let last_play =
+            |file: &AudioFile| self.play_times.get(&file.path).map(|time| time.to_utc());
+
+        let (ready, cooling_down): (Vec<_>, Vec<_>) =
+            candidates.into_iter().partition(|file| {
+                match (file.config.cooldown_seconds, last_play(file)) {
+                    (Some(cooldown), Some(last_play)) => {
+                        now - last_play >= TimeDelta::seconds(cooldown as i64)
+                    }
+                    _ => true,
+                }
+            });
+        if !ready.is_empty() {
+            return ready;
+        }
+
+        // Every candidate here has a last play, as the others would be ready
+        cooling_down
+            .into_iter()
+            .min_by_key(|file| last_play(file))
+            .into_iter()
+            .collect()
+    }
+
+    /// Removes the sounds already played in this round from the candidates when using the shuffle bag.
+    /// Once every candidate has been played, a new round starts with all of them.
+    fn exclude_drawn<'a>(&mut self, candidates: Vec<&'a AudioFile>) -> Vec<&'a AudioFile> {
+        if self.config.general.selection_mode != SelectionMode::ShuffleBag {
+            return candidates;
+        }
+        let remaining: Vec<_> = candidates
+            .iter()
+            .copied()
+            .filter(|file| !self.drawn.contains(&file.path))
+            .collect();
+        if !remaining.is_empty() {
+            return remaining;
+        }
+        debug!("Every sound has been played, starting a new round");
+        for file in &candidates {
+            self.drawn.remove(&file.path);
+        }
+        candidates
+    }
+
+    /// Removes the most recently played sounds from the candidates when `no_repeat` is enabled.
+    ///
+    /// If that would leave nothing to play, fewer of the recent sounds are avoided,
+    /// so the sound played longest ago is the first to be allowed again.
+    fn exclude_recent<'a>(&self, sounds: &'a [AudioFile]) -> Vec<&'a AudioFile> {
+        let mut avoid = if self.config.general.no_repeat {
+            self.recent.len()
+        } else {
+            0
+        };
+
+        loop {
+            let candidates: Vec<_> = sounds
+                .iter()
+                .filter(|file| {
+                    !self
+                        .recent
+                        .iter()
+                        .rev()
+                        .take(avoid)
+                        .any(|path| *path == file.path)
+                })
+                .collect();
+            if !candidates.is_empty() || avoid == 0 {
+                return candidates;
+            }
+            avoid -= 1;
+        }
+    }
+
+    pub fn schedule_new_play(&mut self) {
+        /// How long to wait before trying again when the schedule has no valid time
+        const IDLE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+        let mut then = next_play_time(self.now(), &self.config, &mut self.rng);
+        self.save_rng();
+
+        // Cutting out invalid time can bring the next play closer to the last one than the bounds allow,
+        // so make sure to keep the minimum gap to the last sound that was actually played.
+        // Plays that were skipped because the time was invalid never update the last play, so they don't count.
+        if let (Some(next), Ok(last_play)) = (then, self.read_time(&self.last_play_path)) {
+            then = keep_min_gap(&self.config, next, last_play);
+            if let Some(kept) = then.filter(|kept| *kept != next) {
+                info!("Next play too close to last play @ {last_play}, pushing it to {kept}");
+            }
+        }
+
+        // Once today's plays are used up, wait for the next day with valid time instead.
+        // Like after any other invalid time, the interval starts counting when the valid time begins,
+        // so that the first play of the day doesn't always come right at the start.
+        let limit_reached = self.daily_limit_reached();
+        let today = self.play_day();
+        if limit_reached {
+            if let Some(next) = then.filter(|next| self.config.schedule.play_day(*next) == today) {
+                then = self
+                    .config
+                    .schedule
+                    .next_play_day_start(today)
+                    .and_then(|start| next_play_time(start, &self.config, &mut self.rng));
+                self.save_rng();
+                info!(
+                    "Maximum plays for today reached, pushing next play @ {next} to the next day"
+                );
+            }
+        }
+
+        // When queueing, the next sound can't start before the current one has ended anyway,
+        // so schedule it for when it actually will play
+        if self.config.general.overrun_policy == OverrunPolicy::Queue
+            && !self.config.general.allow_overlap
+            && self.config.general.crossfade_ms == 0
+        {
+            if let (Some(next), Some(end)) = (then, self.playing_until) {
+                if next < end {
+                    then = self.config.schedule.find_next_valid_time(end);
+                    info!("Next play @ {next} would overlap the current sound, pushing it to after it ends @ {end}");
+                }
+            }
+        }
+
+        // Fixed times play regardless of the random interval, so whichever comes first is the next play
+        // and they don't play either once today's plays are used up
+        let mut fixed = self.config.schedule.next_fixed_time(self.now());
+        while let Some(time) =
+            fixed.filter(|time| limit_reached && self.config.schedule.play_day(*time) == today)
+        {
+            fixed = self.config.schedule.next_fixed_time(time);
+        }
+        if let Some(fixed) = fixed {
+            if then.is_none_or(|then| fixed <= then) {
+                then = Some(fixed);
+            }
+        }
+
+        let delay_end = self.startup_delay_end();
+        if let Some(next) = then.filter(|next| *next < delay_end) {
+            then = self.config.schedule.find_next_valid_time(delay_end);
+            info!("Next play @ {next} is within startup_delay_seconds, pushing it to {delay_end}");
+        }
+
+        let Some(then) = then else {
+            error!(
+                "No valid time to play within {MAX_SEARCH_DAYS} days, check the schedule. Trying again in {} minutes",
+                IDLE_DURATION.as_secs() / 60
+            );
+            self.sleep.as_mut().reset(Instant::now() + IDLE_DURATION);
+            return;
+        };
+
+        self.reschedule_at = self.lookahead_horizon(then);
+        match self.reschedule_at {
+            Some(horizon) => info!(
+                "Next play @ {then}, which is further away than max_lookahead_seconds. Rescheduling @ {horizon}"
+            ),
+            // With the offset, so that the time is clear even when the system is in another timezone
+            None => info!("Next play @ {}", self.localize(then)),
+        }
+        metrics::set_next_play(self.localize(then));
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.next_play(self.localize(then));
+        }
+        self.emit(Event::Scheduled {
+            next_play: self.localize(then),
+        });
+
+        // Write the next play to file, so that it survives speaker reboot
+        self.next_play = Some(self.localize(then));
+        if self.config.general.persist_schedule {
+            match self.write_time(&self.next_play_path, then) {
+                Ok(()) => self.next_play = None,
+                Err(e) => warn!(
+                    "Could not write next-play file {}, keeping the schedule in memory: {e}",
+                    self.next_play_path.display()
+                ),
+            }
+        }
+
+        self.sleep_until(then);
+    }
+
+    /// The time until which nothing is played on schedule after starting
+    fn startup_delay_end(&self) -> NaiveDateTime {
+        let delay = TimeDelta::seconds(self.config.general.startup_delay_seconds as i64);
+        self.to_local((self.started + delay).fixed_offset())
+    }
+
+    /// The time until which `min_gap_seconds` keeps anything from playing on schedule, if that is still to come
+    fn min_gap_end(&self) -> Option<NaiveDateTime> {
+        let last_play = self.read_time(&self.last_play_path).ok()?;
+        let end = last_play + TimeDelta::seconds(self.config.general.min_gap_seconds as i64);
+        (end > self.now()).then_some(end)
+    }
+
+    /// Prints the event to stdout with `--json`
+    fn emit(&self, event: Event) {
+        if self.json {
+            events::print(self.clock.now(), &event);
+        }
+    }
+
+    /// Whether `max_plays_per_day` sounds have already been played today
+    fn daily_limit_reached(&self) -> bool {
+        self.config
+            .general
+            .max_plays_per_day
+            .is_some_and(|max| self.plays_on(self.play_day()) >= max)
+    }
+
+    /// The day plays currently count towards, see [`Schedule::play_day`]
+    fn play_day(&self) -> NaiveDate {
+        self.config.schedule.play_day(self.now())
+    }
+
+    /// Reads the number of sounds played on the given date.
+    /// The file only holds the count of the last day anything was played, so any other day has no plays.
+    fn plays_on(&self, date: NaiveDate) -> usize {
+        let Ok(contents) = std::fs::read_to_string(&self.play_count_path) else {
+            return 0;
+        };
+        let Some((day, count)) = contents.trim().split_once(' ') else {
+            warn!("Invalid play-count file, ignoring it");
+            return 0;
+        };
+        match (day.parse::<NaiveDate>(), count.parse()) {
+            (Ok(day), Ok(count)) if day == date => count,
+            (Ok(_), Ok(_)) => 0,
+            _ => {
+                warn!("Invalid play-count file, ignoring it");
+                0
+            }
+        }
+    }
+
+    fn sleep_until(&mut self, time: NaiveDateTime) {
+        let diff = self.until(time);
+        debug!(
+            "Sleeping until {}, which is {} seconds",
+            time,
+            diff.num_seconds()
+        );
+        // We should simply wait
+        self.reset_sleep(diff);
+    }
+
+    /// Returns the latest time to reschedule at according to `max_lookahead_seconds`,
+    /// if a play at `then` is further away than that
+    fn lookahead_horizon(&self, then: NaiveDateTime) -> Option<NaiveDateTime> {
+        let max = self.config.general.max_lookahead_seconds?;
+        let horizon = self.now() + TimeDelta::seconds(max as i64);
+        (then > horizon).then_some(horizon)
+    }
+
+    /// Sleeps for the given duration.
+    /// With profiles, this wakes up at midnight at the latest, so that a change of profile is noticed.
+    /// It also wakes up in time to reschedule for `max_lookahead_seconds`.
+    fn reset_sleep(&mut self, mut diff: TimeDelta) {
+        if let Some(at) = self.reschedule_at {
+            diff = diff.min(self.until(at));
+        }
+        if !self.config_file.profiles.is_empty() {
+            if let Some(tomorrow) = self.today().succ_opt() {
+                let midnight = self
+                    .config_file
+                    .general
+                    .localize(tomorrow.and_time(NaiveTime::MIN));
+                diff = diff.min(midnight.to_utc() - self.clock.now());
+            }
+        }
+        self.sleep
+            .as_mut()
+            .reset(Instant::now() + diff.to_std().unwrap_or_default());
+    }
+
+    /// The current date in the timezone of the top-level config, which decides what profile applies
+    fn today(&self) -> NaiveDate {
+        self.config_file
+            .general
+            .to_local(self.clock.now().fixed_offset())
+            .date()
+    }
+
+    /// The current time in the configured timezone
+    fn now(&self) -> NaiveDateTime {
+        self.to_local(self.clock.now().fixed_offset())
+    }
+
+    fn to_local(&self, time: DateTime<FixedOffset>) -> NaiveDateTime {
+        self.config.general.to_local(time)
+    }
+
+    fn localize(&self, time: NaiveDateTime) -> DateTime<FixedOffset> {
+        self.config.general.localize(time)
+    }
+
+    /// How long it is until the given local time, taking DST transitions into account
+    fn until(&self, time: NaiveDateTime) -> TimeDelta {
+        self.localize(time).signed_duration_since(self.clock.now())
+    }
+
+    /// Reads the scheduled play from memory if it is kept there, or else from the next-play file
+    fn read_next_play(&self) -> anyhow::Result<NaiveDateTime> {
+        if let Some(time) = self.next_play {
+            return Ok(self.to_local(time));
+        }
+        if self.config.general.persist_schedule {
+            return self.read_time(&self.next_play_path);
+        }
+        Err(anyhow::anyhow!("no play scheduled"))
+    }
+
+    /// Reads a time written by `write_time`, as a local time in the configured timezone
+    fn read_time(&self, path: &Path) -> anyhow::Result<NaiveDateTime> {
+        let contents = std::fs::read_to_string(path)?;
+        let contents = contents.trim();
+        match DateTime::parse_from_rfc3339(contents) {
+            Ok(time) => Ok(self.to_local(time)),
+            // Files written without an offset are already in local time
+            Err(_) => Ok(contents.parse()?),
+        }
+    }
+
+    /// Writes a local time in the configured timezone to a file, including its UTC offset
+    /// so that it stays unambiguous across DST transitions and timezone changes
+    fn write_time(&self, path: &Path, time: NaiveDateTime) -> std::io::Result<()> {
+        write_atomic(path, format!("{}\n", self.localize(time).to_rfc3339()))
+    }
+}
+
+/// A line in the play history file, also published to MQTT
+#[derive(Debug, Serialize)]
+struct HistoryEntry {
+    time: DateTime<FixedOffset>,
+    path: PathBuf,
+    reason: PlayReason,
+}
+
+/// What caused a sound to be played
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayReason {
+    /// The randomly scheduled next play was reached
+    Scheduled,
+    /// One of the `fixed_times` was reached
+    Fixed,
+    /// A play was requested through a command, e.g. over HTTP, MQTT or stdin
+    Manual,
+}
+
+impl std::fmt::Display for PlayReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PlayReason::Scheduled => "scheduled",
+            PlayReason::Fixed => "fixed",
+            PlayReason::Manual => "manual",
+        })
+    }
+}
+
+fn append_history(path: &Path, entry: &HistoryEntry) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+
+    // The history is only ever appended to, so rewriting it for every play would be wasteful.
+    // Writing the whole line at once means an interrupted write can at most leave a partial last line.
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Replaces the contents of a file, so that it either has its old or its new contents even if the
+/// write is interrupted, e.g. by losing power. The contents are written to a temporary file next to it,
+/// which is then renamed over it.
+fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = std::fs::File::create(&temp_path)?;
+    file.write_all(contents.as_ref())?;
+    // Make sure the contents are on disk before the rename is, or the rename could survive without them
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp_path, path)
+}
+
+/// Decides by name, size and duration which files and directories in the sounds directory are played
+struct SoundFilter {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+    max_duration: Option<Duration>,
+    max_bytes: Option<u64>,
+}
+
+impl SoundFilter {
+    /// Files that are never played, in addition to the configured excludes
+    const ALWAYS_EXCLUDED: &[&str] = &["config.toml"];
+
+    /// Creates a filter from the configured patterns. Invalid patterns are rejected when the config is loaded,
+    /// so they are simply left out here.
+    fn new(general: &General) -> Self {
+        fn compile<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Vec<glob::Pattern> {
+            patterns
+                .into_iter()
+                .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+                .collect()
+        }
+
+        Self {
+            include: compile(general.include.iter().map(String::as_str)),
+            exclude: compile(
+                Self::ALWAYS_EXCLUDED
+                    .iter()
+                    .copied()
+                    .chain(general.exclude.iter().map(String::as_str)),
+            ),
+            max_duration: general
+                .max_duration_seconds
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()),
+            max_bytes: general.max_file_bytes,
+        }
+    }
+
+    /// Returns why the file is too large or too long to be played, if it is
+    fn exceeds_limits(&self, path: &Path) -> Option<String> {
+        if let Some(max_bytes) = self.max_bytes {
+            let size = std::fs::metadata(path)
+                .map(|metadata| metadata.len())
+                .ok()?;
+            if size > max_bytes {
+                return Some(format!(
+                    "{size} bytes is larger than max_file_bytes ({max_bytes})"
+                ));
+            }
+        }
+
+        if let Some(max_duration) = self.max_duration {
+            // Only the header is decoded, so this is cheap
+            let duration = open_sound(path).ok()?.total_duration()?;
+            if duration > max_duration {
+                return Some(format!(
+                    "{:.1} seconds is longer than max_duration_seconds ({})",
+                    duration.as_secs_f64(),
+                    max_duration.as_secs_f64()
+                ));
+            }
+        }
+
+        None
+    }
+
+    fn allows_file(&self, name: &str) -> bool {
+        self.allows_dir(name)
+            && (self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches(name)))
+    }
+
+    /// Directories are only excluded, as the includes are meant for the files in them
+    fn allows_dir(&self, name: &str) -> bool {
+        !self.exclude.iter().any(|pattern| pattern.matches(name))
+    }
+}
+
+/// File extensions of the formats we are able to decode
+const AUDIO_EXTENSIONS: &[&str] = &["wav", "mp3", "ogg", "oga", "flac"];
+
+fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            AUDIO_EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
+}
+
+/// Loudness of a sound, with samples scaled to be from -1 to 1
+struct Loudness {
+    rms: f32,
+    peak: f32,
+}
+
+fn measure_loudness(path: &Path) -> anyhow::Result<Loudness> {
+    let mut sum_of_squares = 0.0;
+    let mut peak: f32 = 0.0;
+    let mut count = 0usize;
+    for sample in open_sound(path)? {
+        let sample = sample as f32 / i16::MAX as f32;
+        sum_of_squares += (sample * sample) as f64;
+        peak = peak.max(sample.abs());
+        count += 1;
+    }
+
+    let rms = if count > 0 {
+        (sum_of_squares / count as f64).sqrt() as f32
+    } else {
+        0.0
+    };
+    Ok(Loudness { rms, peak })
+}
+
+fn open_sound(path: &Path) -> anyhow::Result<Decoder<BufReader<std::fs::File>>> {
+    let file = std::fs::File::open(path)?;
+    Ok(Decoder::new(BufReader::new(file))?)
+}
+
+/// Warns if the sounds don't all have the same sample rate and channels.
+/// They are converted when played, but conversion can affect quality, so it is better to keep them uniform.
+fn warn_mixed_formats(sounds: &[AudioFile]) {
+    let mut formats: HashMap<(u32, u16), usize> = HashMap::new();
+    for sound in sounds {
+        match open_sound(&sound.path) {
+            Ok(decoder) => {
+                *formats
+                    .entry((decoder.sample_rate(), decoder.channels()))
+                    .or_default() += 1
+            }
+            Err(e) => debug!("Could not check format of {}: {e}", sound.path.display()),
+        }
+    }
+
+    if formats.len() > 1 {
+        let mut formats: Vec<_> = formats.into_iter().collect();
+        formats.sort();
+        let formats: Vec<_> = formats
+            .iter()
+            .map(|((rate, channels), count)| {
+                format!("{count} at {rate} Hz with {channels} channels")
+            })
+            .collect();
+        warn!("Sounds have mixed formats: {}", formats.join(", "));
+    }
+}
+
+/// Picks a directory among those the candidates are in by their `folder_weight`, then a sound in it by their weight
+fn pick_by_folder<'a>(
+    candidates: &[&'a AudioFile],
+    rng: &mut (impl Rng + ?Sized),
+) -> Option<&'a AudioFile> {
+    // Each directory counts once, no matter how many of its sounds are candidates
+    let mut folders: Vec<(&Path, f32)> = vec![];
+    for file in candidates {
+        let folder = file.path.parent()?;
+        if !folders.iter().any(|(other, _)| *other == folder) {
+            folders.push((folder, file.folder_weight));
+        }
+    }
+
+    let (folder, _) = folders.choose_weighted(rng, |(_, weight)| *weight).ok()?;
+    let files: Vec<_> = candidates
+        .iter()
+        .filter(|file| file.path.parent() == Some(folder))
+        .collect();
+    // Weights are normalized per directory, so within a directory they keep their configured proportions
+    files
+        .choose_weighted(rng, |file| file.config.weight)
+        .ok()
+        .map(|file| **file)
+}
+
+/// Fades out the last `fade` of a source that is `total` long
+fn fade_out_at_end<S: Source<Item = i16>>(
+    source: S,
+    total: Duration,
+    fade: Duration,
+) -> impl Source<Item = i16> {
+    const STEP: Duration = Duration::from_millis(10);
+
+    let fade = fade.min(total);
+    let fade_start = total - fade;
+    let mut elapsed = Duration::ZERO;
+    source.amplify(1.0).periodic_access(STEP, move |source| {
+        if elapsed >= fade_start {
+            let remaining = total.saturating_sub(elapsed);
+            source.set_factor(remaining.as_secs_f32() / fade.as_secs_f32());
+        }
+        elapsed += STEP;
+    })
+}
+
+fn localize<Tz: TimeZone>(tz: &Tz, time: NaiveDateTime) -> DateTime<FixedOffset> {
+    match tz.from_local_datetime(&time) {
+        // When the clock is turned back, the earliest of the two is when the time is first reached
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time.fixed_offset(),
+        // The time was skipped when the clock was turned forward, so move past the gap
+        LocalResult::None => localize(tz, time + TimeDelta::hours(1)),
+    }
+}
+
+/// The `config.toml` of a directory of sounds.
+///
+/// `fade_in_ms`, `fade_out_ms` and `cooldown_seconds` are the defaults for all files in the directory and its subdirectories,
+/// unless overridden by a subdirectory or in `files`, where the innermost setting wins.
+/// `volume` instead multiplies with the volumes of the parent directories and the files.
+#[derive(Debug, Deserialize)]
+struct DirectoryConfig {
+    #[serde(default = "default_volume")]
+    volume: f32,
+    /// How often a sound in the directory is picked compared to the files and directories next to it.
+    /// Within the directory, its share is split between its own entries by their weights.
+    #[serde(default = "default_weight")]
+    weight: f32,
+    fade_in_ms: Option<u64>,
+    fade_out_ms: Option<u64>,
+    /// Also accepted as `cooldown`, which may be a human readable duration like `"6h"`
+    #[serde(
+        default,
+        alias = "cooldown",
+        deserialize_with = "deserialize_optional_seconds"
+    )]
+    cooldown_seconds: Option<u64>,
+    /// How often the directory is picked compared to others, with the `folder` selection mode
+    #[serde(default = "default_weight")]
+    folder_weight: f32,
+    /// Set to `false` to never play anything in the directory or its subdirectories
+    #[serde(default = "default_true")]
+    enabled: bool,
+    /// Set to `false` to only play the files directly in the directory, ignoring its subdirectories
+    #[serde(default = "default_true")]
+    recursive: bool,
+    /// Config for individual files in the directory, keyed by file name.
+    /// A `<name>.toml` next to a file takes precedence over its entry here.
+    #[serde(default)]
+    files: HashMap<String, FileSettings>,
+    /// Tags applied to every file in the directory and its subdirectories
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl DirectoryConfig {
+    /// Loads the `config.toml` in the given directory, falling back to the default config if there is none
+    fn load(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join("config.toml");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Like [`Self::load`], but logs errors and falls back to the default config
+    fn load_or_default(dir: &Path) -> Self {
+        Self::load(dir).unwrap_or_else(|e| {
+            warn!("Error reading config for {}: {e}", dir.display());
+            Self::default()
+        })
+    }
+
+    /// The settings this directory gives its contents
+    fn defaults(&self) -> FileSettings {
+        FileSettings {
+            weight: None,
+            volume: None,
+            fade_in_ms: self.fade_in_ms,
+            fade_out_ms: self.fade_out_ms,
+            cooldown_seconds: self.cooldown_seconds,
+            title: None,
+            vary_speed: None,
+            start_time: None,
+            end_time: None,
+            weekdays: Vec::new(),
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl Default for DirectoryConfig {
+    fn default() -> Self {
+        Self {
+            volume: default_volume(),
+            weight: default_weight(),
+            fade_in_ms: None,
+            fade_out_ms: None,
+            cooldown_seconds: None,
+            folder_weight: default_weight(),
+            enabled: true,
+            recursive: true,
+            files: HashMap::new(),
+            tags: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AudioFile {
+    path: PathBuf,
+    config: FileConfig,
+    /// Tags of all directories the file is in
+    tags: Vec<String>,
+    /// Weight of the directory the file is directly in
+    folder_weight: f32,
+}
+
+/// The effective settings of a file
+#[derive(Debug, Clone)]
+struct FileConfig {
+    weight: f32,
+    volume: f32,
+    fade_in_ms: Option<u64>,
+    fade_out_ms: Option<u64>,
+    /// Minimum number of seconds before the file may be played again
+    cooldown_seconds: Option<u64>,
+    title: Option<String>,
+    vary_speed: bool,
+    start_time: Option<NaiveTime>,
+    end_time: Option<NaiveTime>,
+    /// Days the file may be played on, or empty for every day
+    weekdays: Vec<Weekday>,
+}
+
+impl FileConfig {
+    /// Whether the file's own restrictions allow playing it at the given time
+    fn allowed_at(&self, time: NaiveDateTime) -> bool {
+        if !self.weekdays.is_empty() && !self.weekdays.contains(&time.weekday()) {
+            return false;
+        }
+        let start = self.start_time.unwrap_or(NaiveTime::MIN);
+        let end = self
+            .end_time
+            .unwrap_or(NaiveTime::from_hms_nano_opt(23, 59, 59, 999_999_999).unwrap());
+        if start <= end {
+            (start..=end).contains(&time.time())
+        } else {
+            time.time() >= start || time.time() <= end
+        }
+    }
+}
+
+/// Settings for a file as written in a directory's `config.toml` or in a `<name>.toml` next to the file,
+/// where anything left out is inherited
+#[derive(Debug, Deserialize, Clone, Default)]
+struct FileSettings {
+    weight: Option<f32>,
+    volume: Option<f32>,
+    fade_in_ms: Option<u64>,
+    fade_out_ms: Option<u64>,
+    /// Also accepted as `cooldown`, which may be a human readable duration like `"6h"`
+    #[serde(
+        default,
+        alias = "cooldown",
+        deserialize_with = "deserialize_optional_seconds"
+    )]
+    cooldown_seconds: Option<u64>,
+    /// Name to announce the file by, instead of its file name
+    title: Option<String>,
+    /// Set to `false` to always play the file at its normal speed, even with `speed_variation`
+    vary_speed: Option<bool>,
+    /// Only play the file from this time of day, in addition to the schedule
+    start_time: Option<NaiveTime>,
+    /// Only play the file until this time of day. If it is before `start_time`, the allowed time wraps around midnight.
+    end_time: Option<NaiveTime>,
+    /// Only play the file on these days, in the same format as the `weekdays` of the schedule
+    #[serde(default, deserialize_with = "deserialize_weekdays")]
+    weekdays: Vec<Weekday>,
+    /// Tags of the file, in addition to those of the directories it is in
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl FileSettings {
+    /// Loads the settings in the `<name>.toml` next to a sound file, if there is one.
+    /// A file named `config` would get the directory's config, so it has no sidecar.
+    fn load_sidecar(file: &Path) -> anyhow::Result<Self> {
+        let path = file.with_extension("toml");
+        if file.file_stem().is_some_and(|stem| stem == "config") || !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Fills in the settings left out here with those of `parent`
+    fn or(&self, parent: &FileSettings) -> FileSettings {
+        FileSettings {
+            weight: self.weight.or(parent.weight),
+            volume: self.volume.or(parent.volume),
+            fade_in_ms: self.fade_in_ms.or(parent.fade_in_ms),
+            fade_out_ms: self.fade_out_ms.or(parent.fade_out_ms),
+            cooldown_seconds: self.cooldown_seconds.or(parent.cooldown_seconds),
+            title: self.title.clone().or_else(|| parent.title.clone()),
+            vary_speed: self.vary_speed.or(parent.vary_speed),
+            start_time: self.start_time.or(parent.start_time),
+            end_time: self.end_time.or(parent.end_time),
+            weekdays: if self.weekdays.is_empty() {
+                parent.weekdays.clone()
+            } else {
+                self.weekdays.clone()
+            },
+            tags: self.tags.iter().chain(&parent.tags).cloned().collect(),
+        }
+    }
+
+    /// Fills in the settings that are still left out with their defaults.
+    /// Fades left out fall back to the general config when played.
+    fn resolve(&self) -> FileConfig {
+        FileConfig {
+            weight: self.weight.unwrap_or(default_weight()),
+            volume: self.volume.unwrap_or(default_volume()),
+            fade_in_ms: self.fade_in_ms,
+            fade_out_ms: self.fade_out_ms,
+            cooldown_seconds: self.cooldown_seconds,
+            title: self.title.clone(),
+            vary_speed: self.vary_speed.unwrap_or(true),
+            start_time: self.start_time,
+            end_time: self.end_time,
+            weekdays: self.weekdays.clone(),
+        }
+    }
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+const fn default_weight() -> f32 {
+    1.0
+}
+
+const fn default_volume() -> f32 {
+    1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::SubsecRound;
+
+    use super::*;
+
+    /// A directory under the system temp directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("speakthing-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        /// Writes a file at a path relative to the directory, creating its parents
+        fn write(&self, path: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A context without audio output that keeps its state in `dir`. Needs a Tokio runtime.
+    fn context(dir: &TempDir) -> Context {
+        context_with_clock(dir, Arc::new(ManualClock::new(Utc::now())))
+    }
+
+    /// Like [`context`], with a clock that can be moved while the context is using it
+    fn context_with_clock(dir: &TempDir, clock: Arc<ManualClock>) -> Context {
+        let (context, _) = Context::new(
+            None,
+            Box::new(clock),
+            Options {
+                config: dir.0.join("config.toml"),
+                sounds_dir: dir.0.join("sounds"),
+                next_play: dir.0.join("next-play"),
+                last_play: dir.0.join("last-play"),
+                play_count: dir.0.join("play-count"),
+                play_times: dir.0.join("play-times"),
+                rng_state: dir.0.join("rng-state"),
+                ..Options::default()
+            },
+        );
+        context
+    }
+
+    /// The weight of each sound, by its path relative to `dir`
+    fn weights(dir: &TempDir, sounds: &[AudioFile]) -> HashMap<String, f32> {
+        sounds
+            .iter()
+            .map(|sound| {
+                let path = sound.path.strip_prefix(&dir.0).unwrap();
+                (path.display().to_string(), sound.config.weight)
+            })
+            .collect()
+    }
+
+    fn assert_weights(weights: &HashMap<String, f32>, expected: &[(&str, f32)]) {
+        assert_eq!(weights.len(), expected.len(), "{weights:?}");
+        for (path, weight) in expected {
+            let actual = weights[*path];
+            assert!(
+                (actual - weight).abs() < 1e-6,
+                "{path}: {actual} != {weight}"
+            );
+        }
+        let total: f32 = weights.values().sum();
+        assert!((total - 1.0).abs() < 1e-6, "weights sum to {total}");
+    }
+
+    /// A config with the given `[general]` settings and `[schedule]`,
+    /// in UTC so that results don't depend on the system timezone
+    fn config_contents(general: &str, schedule: &str) -> String {
+        format!(
+            "[general]\nlower_bound = 600\nupper_bound = 3600\ntimezone = \"UTC\"\n{general}\n[schedule]\n{schedule}"
+        )
+    }
+
+    fn config(general: &str, schedule: &str) -> BaseConfig {
+        BaseConfig::parse(&config_contents(general, schedule)).unwrap()
+    }
+
+    fn schedule(schedule: &str) -> Schedule {
+        config("", schedule).schedule
+    }
+
+    /// Weekdays from 9 to 17, the schedule most tests use
+    const OFFICE_HOURS: &str = r#"
+        weekdays = "mon-fri"
+        start_time = "09:00:00"
+        end_time = "17:00:00"
+    "#;
+
+    fn office_hours() -> Schedule {
+        schedule(OFFICE_HOURS)
+    }
+
+    fn at(time: &str) -> NaiveDateTime {
+        time.parse().unwrap()
+    }
+
+    fn time(time: &str) -> NaiveTime {
+        time.parse().unwrap()
+    }
+
+    fn window(start: &str, end: &str) -> TimeWindow {
+        TimeWindow {
+            start_time: time(start),
+            end_time: time(end),
+            tags: Vec::new(),
+            sounds_dir: None,
+        }
+    }
+
+    fn bounds(windows: &[TimeWindow]) -> Vec<(NaiveTime, NaiveTime)> {
+        windows
+            .iter()
+            .map(|window| (window.start_time, window.end_time))
+            .collect()
+    }
+
+    const LAST_NANOSECOND: &str = "23:59:59.999999999";
+
+    #[test]
+    fn without_cuts_out_the_middle() {
+        let parts =
+            window("09:00:00", "17:00:00").without(time("12:00:00"), time("12:59:59.999999999"));
+        assert_eq!(
+            bounds(&parts),
+            [
+                (time("09:00:00"), time("11:59:59.999999999")),
+                (time("13:00:00"), time("17:00:00")),
+            ]
+        );
+    }
+
+    #[test]
+    fn without_cuts_off_either_end() {
+        let window = window("09:00:00", "17:00:00");
+        assert_eq!(
+            bounds(&window.without(time("08:00:00"), time("09:59:59.999999999"))),
+            [(time("10:00:00"), time("17:00:00"))]
+        );
+        assert_eq!(
+            bounds(&window.without(time("16:00:00"), time("18:00:00"))),
+            [(time("09:00:00"), time("15:59:59.999999999"))]
+        );
+    }
+
+    #[test]
+    fn without_leaves_nothing_when_covered() {
+        let window = window("09:00:00", "17:00:00");
+        assert!(window
+            .without(time("09:00:00"), time("17:00:00"))
+            .is_empty());
+        assert!(window
+            .without(time("08:00:00"), time("18:00:00"))
+            .is_empty());
+    }
+
+    #[test]
+    fn without_keeps_windows_it_does_not_touch() {
+        let window = window("09:00:00", "17:00:00");
+        assert_eq!(
+            bounds(&window.without(time("07:00:00"), time("08:59:59.999999999"))),
+            [(time("09:00:00"), time("17:00:00"))]
+        );
+        assert_eq!(
+            bounds(&window.without(time("17:00:01"), time("18:00:00"))),
+            [(time("09:00:00"), time("17:00:00"))]
+        );
+    }
+
+    #[test]
+    fn busy_events_are_cut_out_of_windows() {
+        let mut schedule = office_hours();
+        schedule.set_calendar(
+            CalendarMode::Busy,
+            vec![(at("2026-10-12T10:00:00"), at("2026-10-12T11:00:00"))],
+        );
+        let monday = at("2026-10-12T00:00:00").date();
+        assert_eq!(
+            bounds(&schedule.windows(monday)),
+            [
+                (time("09:00:00"), time("09:59:59.999999999")),
+                (time("11:00:00"), time("17:00:00")),
+            ]
+        );
+        assert!(!schedule.is_time_valid(at("2026-10-12T10:30:00")));
+        assert!(schedule.is_time_valid(at("2026-10-12T11:00:00")));
+    }
+
+    #[test]
+    fn busy_events_over_midnight_are_cut_out_of_both_days() {
+        let mut schedule = office_hours();
+        schedule.set_calendar(
+            CalendarMode::Busy,
+            vec![(at("2026-10-12T16:00:00"), at("2026-10-13T10:00:00"))],
+        );
+        assert_eq!(
+            bounds(&schedule.windows(at("2026-10-12T00:00:00").date())),
+            [(time("09:00:00"), time("15:59:59.999999999"))]
+        );
+        assert_eq!(
+            bounds(&schedule.windows(at("2026-10-13T00:00:00").date())),
+            [(time("10:00:00"), time("17:00:00"))]
+        );
+        assert_eq!(
+            schedule.find_next_valid_time(at("2026-10-12T16:30:00")),
+            Some(at("2026-10-13T10:00:00"))
+        );
+    }
+
+    #[test]
+    fn offset_skips_the_gap_between_windows() {
+        let schedule = schedule(
+            r#"
+            weekdays = "mon"
+            windows = [
+                { start_time = "09:00:00", end_time = "12:00:00" },
+                { start_time = "13:00:00", end_time = "17:00:00" },
+            ]
+            "#,
+        );
+        assert_eq!(
+            schedule.add_valid_time(at("2026-10-12T11:00:00"), TimeDelta::hours(2)),
+            Some(at("2026-10-12T14:00:00"))
+        );
+        // Starting in the gap counts from the start of the next window
+        assert_eq!(
+            schedule.add_valid_time(at("2026-10-12T12:30:00"), TimeDelta::minutes(30)),
+            Some(at("2026-10-12T13:30:00"))
+        );
+    }
+
+    /// Friday night from 22 to 2 on Saturday
+    fn friday_night() -> Schedule {
+        schedule(
+            r#"
+            weekdays = "fri"
+            start_time = "22:00:00"
+            end_time = "02:00:00"
+            "#,
+        )
+    }
+
+    #[test]
+    fn windows_past_midnight_continue_on_the_next_day() {
+        let schedule = friday_night();
+        let friday = at("2026-10-16T00:00:00").date();
+        assert_eq!(
+            bounds(&schedule.windows(friday)),
+            [(time("22:00:00"), time(LAST_NANOSECOND))]
+        );
+        assert_eq!(
+            bounds(&schedule.windows(friday.succ_opt().unwrap())),
+            [(time("00:00:00"), time("02:00:00"))]
+        );
+        assert!(schedule.is_time_valid(at("2026-10-17T01:00:00")));
+        assert!(!schedule.is_time_valid(at("2026-10-16T01:00:00")));
+    }
+
+    #[test]
+    fn next_valid_time_crosses_day_boundaries() {
+        let schedule = friday_night();
+        assert_eq!(
+            schedule.find_next_valid_time(at("2026-10-16T03:00:00")),
+            Some(at("2026-10-16T22:00:00"))
+        );
+        // After the part on Saturday, the next window is a week later
+        assert_eq!(
+            schedule.find_next_valid_time(at("2026-10-17T02:30:00")),
+            Some(at("2026-10-23T22:00:00"))
+        );
+        assert_eq!(
+            schedule.find_last_valid_time(at("2026-10-17T02:30:00")),
+            Some(at("2026-10-17T02:00:00"))
+        );
+    }
+
+    #[test]
+    fn offset_continues_past_midnight() {
+        let schedule = friday_night();
+        // The window ends on the last nanosecond before midnight, so a nanosecond may be lost at midnight
+        let then = schedule
+            .add_valid_time(at("2026-10-16T23:30:00"), TimeDelta::hours(1))
+            .unwrap();
+        assert_eq!(then.trunc_subsecs(0), at("2026-10-17T00:30:00"));
+    }
+
+    #[test]
+    fn windows_include_their_start_and_end() {
+        let schedule = office_hours();
+        assert!(schedule.is_time_valid(at("2026-10-12T09:00:00")));
+        assert!(schedule.is_time_valid(at("2026-10-12T17:00:00")));
+        assert!(!schedule.is_time_valid(at("2026-10-12T08:59:59.999999999")));
+        assert!(!schedule.is_time_valid(at("2026-10-12T17:00:00.000000001")));
+    }
+
+    #[test]
+    fn boundaries_are_their_own_last_and_next_valid_time() {
+        let schedule = office_hours();
+        for boundary in ["2026-10-12T09:00:00", "2026-10-12T17:00:00"] {
+            assert_eq!(
+                schedule.find_last_valid_time(at(boundary)),
+                Some(at(boundary))
+            );
+            assert_eq!(
+                schedule.find_next_valid_time(at(boundary)),
+                Some(at(boundary))
+            );
+        }
+    }
+
+    #[test]
+    fn last_valid_time_is_the_end_of_the_latest_window() {
+        let schedule = office_hours();
+        // After the end, on the same day
+        assert_eq!(
+            schedule.find_last_valid_time(at("2026-10-13T17:00:00.000000001")),
+            Some(at("2026-10-13T17:00:00"))
+        );
+        // Before the start, on the day before
+        assert_eq!(
+            schedule.find_last_valid_time(at("2026-10-13T08:59:59")),
+            Some(at("2026-10-12T17:00:00"))
+        );
+        // Over the weekend, on Friday
+        assert_eq!(
+            schedule.find_last_valid_time(at("2026-10-12T08:59:59")),
+            Some(at("2026-10-09T17:00:00"))
+        );
+        assert_eq!(
+            schedule.find_last_valid_time(at("2026-10-17T12:00:00")),
+            Some(at("2026-10-16T17:00:00"))
+        );
+    }
+
+    #[test]
+    fn next_valid_time_is_the_start_of_the_earliest_window() {
+        let schedule = office_hours();
+        // Before the start, on the same day
+        assert_eq!(
+            schedule.find_next_valid_time(at("2026-10-12T08:59:59.999999999")),
+            Some(at("2026-10-12T09:00:00"))
+        );
+        // After the end, on the next day
+        assert_eq!(
+            schedule.find_next_valid_time(at("2026-10-12T17:00:01")),
+            Some(at("2026-10-13T09:00:00"))
+        );
+        // Over the weekend, on Monday
+        assert_eq!(
+            schedule.find_next_valid_time(at("2026-10-16T17:00:01")),
+            Some(at("2026-10-19T09:00:00"))
+        );
+        assert_eq!(
+            schedule.find_next_valid_time(at("2026-10-18T12:00:00")),
+            Some(at("2026-10-19T09:00:00"))
+        );
+    }
+
+    #[test]
+    fn valid_times_between_windows_on_the_same_day() {
+        let schedule = schedule(
+            r#"
+            weekdays = "mon"
+            windows = [
+                { start_time = "09:00:00", end_time = "12:00:00" },
+                { start_time = "13:00:00", end_time = "17:00:00" },
+            ]
+            "#,
+        );
+        assert_eq!(
+            schedule.find_last_valid_time(at("2026-10-12T12:30:00")),
+            Some(at("2026-10-12T12:00:00"))
+        );
+        assert_eq!(
+            schedule.find_next_valid_time(at("2026-10-12T12:30:00")),
+            Some(at("2026-10-12T13:00:00"))
+        );
+    }
+
+    #[tokio::test]
+    async fn directory_weights_are_split_between_entries() {
+        let dir = TempDir::new("weights");
+        dir.write("sounds/config.toml", "[files.\"b.wav\"]\nweight = 3\n");
+        dir.write("sounds/a.wav", "");
+        dir.write("sounds/b.wav", "");
+        dir.write("sounds/notes.txt", "");
+        dir.write("sounds/sub/config.toml", "weight = 2\n");
+        dir.write("sounds/sub/c.wav", "");
+        dir.write("sounds/sub/deeper/e.wav", "");
+        dir.write("sounds/sub/deeper/f.wav", "");
+        // Directories without sounds take no weight
+        dir.write("sounds/empty/notes.txt", "");
+
+        let context = context(&dir);
+        let sounds = context.collect_sounds(dir.0.join("sounds"));
+        assert_weights(
+            &weights(&dir, &sounds),
+            &[
+                ("sounds/a.wav", 1.0 / 6.0),
+                ("sounds/b.wav", 3.0 / 6.0),
+                ("sounds/sub/c.wav", 1.0 / 6.0),
+                ("sounds/sub/deeper/e.wav", 1.0 / 12.0),
+                ("sounds/sub/deeper/f.wav", 1.0 / 12.0),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn sound_dirs_are_weighted_against_each_other() {
+        let dir = TempDir::new("sound-dirs");
+        dir.write("one/x.wav", "");
+        dir.write("three/y.wav", "");
+        dir.write("three/z.wav", "");
+        dir.write("empty/notes.txt", "");
+
+        let mut context = context(&dir);
+        context.config = config(
+            &format!(
+                "sound_dirs = [{{ path = '{0}/one', weight = 1 }}, {{ path = '{0}/three', weight = 3 }}, '{0}/empty']",
+                dir.0.display()
+            ),
+            OFFICE_HOURS,
+        );
+        let sounds = context.sounds().to_vec();
+        assert_weights(
+            &weights(&dir, &sounds),
+            &[
+                ("one/x.wav", 0.25),
+                ("three/y.wav", 0.375),
+                ("three/z.wav", 0.375),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn partially_written_temp_files_are_ignored() {
+        let dir = TempDir::new("atomic");
+        let mut context = context(&dir);
+        context.config = config("", OFFICE_HOURS);
+        let path = dir.0.join("next-play");
+
+        context
+            .write_time(&path, at("2026-10-16T10:00:00"))
+            .unwrap();
+        // A write that was interrupted before the rename leaves a truncated temp file next to the old one
+        dir.write("next-play.tmp", "2026-10-16T1");
+        assert_eq!(context.read_time(&path).unwrap(), at("2026-10-16T10:00:00"));
+
+        // The next write replaces the leftover temp file
+        context
+            .write_time(&path, at("2026-10-16T11:00:00"))
+            .unwrap();
+        assert_eq!(context.read_time(&path).unwrap(), at("2026-10-16T11:00:00"));
+        assert!(!dir.0.join("next-play.tmp").exists());
+    }
+
+    #[test]
+    fn offset_spans_several_days() {
+        let schedule = office_hours();
+        // An hour left on Monday, all of Tuesday and Wednesday, and two hours on Thursday
+        assert_eq!(
+            schedule.add_valid_time(at("2026-10-12T16:00:00"), TimeDelta::hours(19)),
+            Some(at("2026-10-15T11:00:00"))
+        );
+        // An hour left on Friday, then Monday and two hours on Tuesday, skipping the weekend
+        assert_eq!(
+            schedule.add_valid_time(at("2026-10-16T16:00:00"), TimeDelta::hours(11)),
+            Some(at("2026-10-20T11:00:00"))
+        );
+        // Starting on the weekend counts from Monday morning
+        assert_eq!(
+            schedule.add_valid_time_in(&Utc, at("2026-10-17T12:00:00"), TimeDelta::hours(17)),
+            Some(at("2026-10-21T10:00:00"))
+        );
+    }
+
+    /// Every night until 6, in which the clock is changed in Europe
+    fn early_mornings() -> Schedule {
+        schedule(
+            r#"
+            weekdays = "mon-sun"
+            start_time = "00:00:00"
+            end_time = "06:00:00"
+            "#,
+        )
+    }
+
+    #[test]
+    fn window_is_shorter_when_the_clock_springs_forward() {
+        let schedule = early_mornings();
+        let stockholm = &chrono_tz::Europe::Stockholm;
+        // The clock goes from 02:00 to 03:00 on the last Sunday of March
+        assert_eq!(
+            schedule.add_valid_time_in(stockholm, at("2026-03-29T01:30:00"), TimeDelta::hours(1)),
+            Some(at("2026-03-29T03:30:00"))
+        );
+        assert_eq!(
+            schedule.add_valid_time_in(stockholm, at("2026-03-29T00:00:00"), TimeDelta::hours(4)),
+            Some(at("2026-03-29T05:00:00"))
+        );
+        // Only 5 hours actually pass in the window, so the rest is counted from the next night
+        assert_eq!(
+            schedule.add_valid_time_in(
+                stockholm,
+                at("2026-03-29T00:00:00"),
+                TimeDelta::minutes(5 * 60 + 30)
+            ),
+            Some(at("2026-03-30T00:30:00"))
+        );
+        assert_eq!(
+            schedule.add_valid_time(at("2026-03-29T00:00:00"), TimeDelta::minutes(5 * 60 + 30)),
+            Some(at("2026-03-29T05:30:00"))
+        );
+    }
+
+    #[test]
+    fn window_is_longer_when_the_clock_falls_back() {
+        let schedule = early_mornings();
+        let stockholm = &chrono_tz::Europe::Stockholm;
+        // The clock goes from 03:00 back to 02:00 on the last Sunday of October
+        assert_eq!(
+            schedule.add_valid_time_in(stockholm, at("2026-10-25T01:30:00"), TimeDelta::hours(1)),
+            Some(at("2026-10-25T02:30:00"))
+        );
+        // 7 hours actually pass in the window, so this still lands in it
+        assert_eq!(
+            schedule.add_valid_time_in(
+                stockholm,
+                at("2026-10-25T00:00:00"),
+                TimeDelta::minutes(6 * 60 + 30)
+            ),
+            Some(at("2026-10-25T05:30:00"))
+        );
+        assert_eq!(
+            schedule.add_valid_time(at("2026-10-25T00:00:00"), TimeDelta::minutes(6 * 60 + 30)),
+            Some(at("2026-10-26T00:30:00"))
+        );
+    }
+
+    #[test]
+    fn second_pass_of_the_repeated_hour_is_moved_past_it() {
+        let schedule = early_mornings();
+        let stockholm = &chrono_tz::Europe::Stockholm;
+        // 02:30 is read as its first pass, so an hour later is the second pass, which can't be told apart from it
+        assert_eq!(
+            schedule.add_valid_time_in(stockholm, at("2026-10-25T02:30:00"), TimeDelta::hours(1)),
+            Some(at("2026-10-25T03:30:00"))
+        );
+        assert_eq!(
+            schedule.add_valid_time_in(stockholm, at("2026-10-25T01:30:00"), TimeDelta::hours(2)),
+            Some(at("2026-10-25T03:30:00"))
+        );
+    }
+
+    #[test]
+    fn normal_intervals_are_centered_and_within_bounds() {
+        let general = config("distribution = \"normal\"", OFFICE_HOURS).general;
+        let mut rng = StdRng::seed_from_u64(259);
+        let samples: Vec<f32> = (0..10_000)
+            .map(|_| general.sample_interval(&mut rng))
+            .collect();
+        assert!(samples
+            .iter()
+            .all(|sample| (600.0..=3600.0).contains(sample)));
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        assert!((mean - 2100.0).abs() < 30.0, "mean is {mean}");
+    }
+
+    #[test]
+    fn wide_normal_intervals_are_clamped_to_the_bounds() {
+        let general = config(
+            "distribution = \"normal\"\nmean = 3000\nstd_dev = 5000",
+            OFFICE_HOURS,
+        )
+        .general;
+        let mut rng = StdRng::seed_from_u64(259);
+        let samples: Vec<f32> = (0..1000)
+            .map(|_| general.sample_interval(&mut rng))
+            .collect();
+        assert!(samples
+            .iter()
+            .all(|sample| (600.0..=3600.0).contains(sample)));
+        // Both bounds are hit, as much of the distribution is outside them
+        assert!(samples.contains(&600.0));
+        assert!(samples.contains(&3600.0));
+    }
+
+    #[test]
+    fn next_play_is_within_the_bounds_of_valid_time() {
+        for distribution in ["uniform", "normal", "exponential"] {
+            let config = config(&format!("distribution = \"{distribution}\""), OFFICE_HOURS);
+            let schedule = &config.schedule;
+            let mut rng = StdRng::seed_from_u64(273);
+            // Inside a window, before one, after the last one of a week and on the weekend
+            for from in [
+                "2026-10-14T12:00:00",
+                "2026-10-14T16:55:00",
+                "2026-10-12T06:00:00",
+                "2026-10-16T20:00:00",
+                "2026-10-17T12:00:00",
+            ] {
+                let from = at(from);
+                let last_valid = schedule.find_last_valid_time(from).unwrap();
+                let earliest = schedule
+                    .add_valid_time(last_valid, TimeDelta::seconds(600))
+                    .unwrap();
+                let latest = schedule
+                    .add_valid_time(last_valid, TimeDelta::seconds(3600))
+                    .unwrap();
+                for _ in 0..200 {
+                    let then = next_play_time(from, &config, &mut rng).unwrap();
+                    assert!(schedule.is_time_valid(then), "{then} is not valid");
+                    assert!(then > from, "{then} is not after {from}");
+                    // Sampled seconds are rounded to what an f32 can hold
+                    let tolerance = TimeDelta::milliseconds(1);
+                    assert!(
+                        then >= earliest - tolerance && then <= latest + tolerance,
+                        "{then} is not between {earliest} and {latest} with {distribution}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn next_play_is_the_same_with_the_same_seed() {
+        let config = config("", OFFICE_HOURS);
+        let from = at("2026-10-14T12:00:00");
+        let play = |seed| next_play_time(from, &config, &mut StdRng::seed_from_u64(seed));
+        assert_eq!(play(1), play(1));
+        assert_ne!(play(1), play(2));
+    }
+
+    #[test]
+    fn no_next_play_in_timetable_mode() {
+        let config = config(
+            "mode = \"timetable\"",
+            &format!("{OFFICE_HOURS}\nfixed_times = [\"12:00:00\"]"),
+        );
+        let mut rng = StdRng::seed_from_u64(273);
+        assert_eq!(
+            next_play_time(at("2026-10-14T12:00:00"), &config, &mut rng),
+            None
+        );
+    }
+
+    /// Plays from Monday 2026-10-12 at the fixed times of the schedule
+    fn fixed_plays(schedule: &Schedule, count: usize) -> Vec<NaiveDateTime> {
+        let mut time = at("2026-10-12T00:00:00");
+        (0..count)
+            .map(|_| {
+                time = schedule.next_fixed_time(time).unwrap();
+                time
+            })
+            .collect()
+    }
+
+    #[test]
+    fn timetables_play_on_their_own_weekdays() {
+        let config = BaseConfig::parse(
+            r#"
+            [general]
+            mode = "timetable"
+
+            [[schedule]]
+            weekdays = "mon"
+            fixed_times = ["10:00:00"]
+
+            [[schedule]]
+            weekdays = "fri"
+            fixed_times = ["15:00:00", "10:30:00"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            fixed_plays(&config.schedule, 5),
+            [
+                at("2026-10-12T10:00:00"),
+                at("2026-10-16T10:30:00"),
+                at("2026-10-16T15:00:00"),
+                at("2026-10-19T10:00:00"),
+                at("2026-10-23T10:30:00"),
+            ]
+        );
+        assert!(!config.schedule.is_fixed_time(at("2026-10-12T15:00:00")));
+        assert!(!config.schedule.is_fixed_time(at("2026-10-16T10:00:00")));
+    }
+
+    #[test]
+    fn fixed_times_need_weekdays() {
+        let error = BaseConfig::parse(
+            r#"
+            [general]
+            mode = "timetable"
+
+            [schedule]
+            fixed_times = ["10:00:00"]
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("`fixed_times` requires `weekdays`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn blackout_dates_only_apply_to_their_own_entry() {
+        let config = BaseConfig::parse(
+            r#"
+            [general]
+            lower_bound = 600
+            upper_bound = 3600
+
+            [[schedule]]
+            weekdays = "mon-fri"
+            start_time = "09:00:00"
+            end_time = "12:00:00"
+            fixed_times = ["10:00:00"]
+            blackout_dates = [2026-10-16]
+
+            [[schedule]]
+            weekdays = "fri"
+            start_time = "13:00:00"
+            end_time = "17:00:00"
+            fixed_times = ["15:00:00"]
+            "#,
+        )
+        .unwrap();
+        let schedule = &config.schedule;
+        let thursday = at("2026-10-15T00:00:00").date();
+        let friday = at("2026-10-16T00:00:00").date();
+        assert_eq!(
+            bounds(&schedule.windows(thursday)),
+            [(time("09:00:00"), time("12:00:00"))]
+        );
+        assert_eq!(
+            bounds(&schedule.windows(friday)),
+            [(time("13:00:00"), time("17:00:00"))]
+        );
+        assert_eq!(*schedule.fixed_times_on(friday), [time("15:00:00")]);
+        assert!(!schedule.is_fixed_time(at("2026-10-16T10:00:00")));
+        assert!(schedule.is_fixed_time(at("2026-10-16T15:00:00")));
+        // The week after, both entries play on Friday again
+        assert_eq!(
+            *schedule.fixed_times_on(at("2026-10-23T00:00:00").date()),
+            [time("10:00:00"), time("15:00:00")]
+        );
+    }
+
+    #[test]
+    fn week_parity_only_applies_to_its_own_entry() {
+        let config = BaseConfig::parse(
+            r#"
+            [general]
+            lower_bound = 600
+            upper_bound = 3600
+
+            [[schedule]]
+            weekdays = "mon"
+            start_time = "09:00:00"
+            end_time = "12:00:00"
+            week_parity = "odd"
+
+            [[schedule]]
+            weekdays = "mon"
+            start_time = "13:00:00"
+            end_time = "17:00:00"
+            "#,
+        )
+        .unwrap();
+        // 2026-10-12 is in week 42
+        assert_eq!(
+            bounds(&config.schedule.windows(at("2026-10-12T00:00:00").date())),
+            [(time("13:00:00"), time("17:00:00"))]
+        );
+        assert_eq!(
+            bounds(&config.schedule.windows(at("2026-10-19T00:00:00").date())),
+            [
+                (time("09:00:00"), time("12:00:00")),
+                (time("13:00:00"), time("17:00:00")),
+            ]
+        );
+    }
+
+    /// A context at the given time in `dir`, with a sound to play and a config reloaded on every wake
+    fn scheduling_context(dir: &TempDir, now: &str) -> (Context, Arc<ManualClock>) {
+        dir.write("config.toml", &config_contents("", OFFICE_HOURS));
+        dir.write("sounds/a.wav", "");
+        let clock = Arc::new(ManualClock::new(at(now).and_utc()));
+        (context_with_clock(dir, clock.clone()), clock)
+    }
+
+    #[tokio::test]
+    async fn first_wake_schedules_a_play_within_the_bounds() {
+        let dir = TempDir::new("first-wake");
+        let (mut context, _) = scheduling_context(&dir, "2026-10-12T10:00:00");
+        context.wake();
+
+        let next_play = context.read_time(&dir.0.join("next-play")).unwrap();
+        assert!(
+            (at("2026-10-12T10:10:00")..=at("2026-10-12T11:00:00")).contains(&next_play),
+            "{next_play}"
+        );
+        assert!(!dir.0.join("last-play").exists());
+    }
+
+    #[tokio::test]
+    async fn play_past_the_end_of_the_window_continues_the_next_day() {
+        let dir = TempDir::new("cut-off");
+        let (mut context, _) = scheduling_context(&dir, "2026-10-16T16:55:00");
+        context.wake();
+
+        // Only 5 minutes are left on Friday, so the rest of the interval is counted from Monday morning
+        let next_play = context.read_time(&dir.0.join("next-play")).unwrap();
+        assert!(
+            (at("2026-10-19T09:05:00")..=at("2026-10-19T09:55:00")).contains(&next_play),
+            "{next_play}"
+        );
+    }
+
+    #[tokio::test]
+    async fn wake_plays_once_the_clock_reaches_the_next_play() {
+        let dir = TempDir::new("reached");
+        let (mut context, clock) = scheduling_context(&dir, "2026-10-12T10:00:00");
+        context.wake();
+        let next_play = context.read_time(&dir.0.join("next-play")).unwrap();
+
+        // Not reached yet, so the play is kept
+        clock.set((next_play - TimeDelta::seconds(1)).and_utc());
+        context.wake();
+        assert_eq!(
+            context.read_time(&dir.0.join("next-play")).unwrap(),
+            next_play
+        );
+        assert!(!dir.0.join("last-play").exists());
+
+        let played = next_play + TimeDelta::seconds(1);
+        clock.set(played.and_utc());
+        context.wake();
+        assert_eq!(context.read_time(&dir.0.join("last-play")).unwrap(), played);
+        assert_eq!(context.plays_on(played.date()), 1);
+        let rescheduled = context.read_time(&dir.0.join("next-play")).unwrap();
+        assert!(
+            rescheduled >= played + TimeDelta::seconds(600),
+            "{rescheduled}"
+        );
+    }
+
+    #[tokio::test]
+    async fn play_reached_after_the_window_has_ended_is_rescheduled() {
+        let dir = TempDir::new("after-window");
+        let (mut context, _) = scheduling_context(&dir, "2026-10-12T17:30:00");
+        context.config = config("", OFFICE_HOURS);
+        context
+            .write_time(&dir.0.join("next-play"), at("2026-10-12T16:59:00"))
+            .unwrap();
+        context.wake();
+
+        // Nothing is played outside the window, and the interval counts from the end of it
+        assert!(!dir.0.join("last-play").exists());
+        let next_play = context.read_time(&dir.0.join("next-play")).unwrap();
+        assert!(
+            (at("2026-10-13T09:10:00")..=at("2026-10-13T10:00:00")).contains(&next_play),
+            "{next_play}"
+        );
+    }
+
+    fn config_error(general: &str) -> String {
+        BaseConfig::parse(&config_contents(general, OFFICE_HOURS))
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn invalid_distributions_are_rejected_on_load() {
+        let error = config_error("distribution = \"exponential\"\nmean = 600");
+        assert!(
+            error.contains("must be greater than lower_bound (600)"),
+            "{error}"
+        );
+        let error = config_error("distribution = \"exponential\"\nmean = 300");
+        assert!(
+            error.contains("must be greater than lower_bound (600)"),
+            "{error}"
+        );
+        let error = config_error("distribution = \"normal\"\nstd_dev = -1");
+        assert!(error.contains("must not be negative"), "{error}");
+        // The default mean is halfway between the bounds, which is always valid
+        config("distribution = \"exponential\"", OFFICE_HOURS);
+    }
+
+    #[test]
+    fn state_files_are_kept_in_the_state_dir() {
+        let dir = TempDir::new("state-dir");
+        let state_dir = dir.0.join("state");
+        let args = Args::parse_from([
+            "speakthing".as_ref(),
+            "--state-dir".as_ref(),
+            state_dir.as_os_str(),
+            "--state-file".as_ref(),
+            "scheduled".as_ref(),
+            "--last-play".as_ref(),
+            "/var/lib/last-play".as_ref(),
+        ]);
+        let options = args.options();
+        assert!(state_dir.is_dir());
+        assert_eq!(options.next_play, state_dir.join("scheduled"));
+        assert_eq!(options.play_count, state_dir.join("play-count"));
+        // Absolute paths are kept as they are
+        assert_eq!(options.last_play, Path::new("/var/lib/last-play"));
+    }
+
+    #[test]
+    fn plays_past_midnight_count_towards_the_day_the_window_started() {
+        let schedule = friday_night();
+        let friday = at("2026-10-16T00:00:00").date();
+        assert_eq!(schedule.play_day(at("2026-10-16T23:00:00")), friday);
+        assert_eq!(schedule.play_day(at("2026-10-17T01:00:00")), friday);
+        assert_eq!(
+            schedule.play_day(at("2026-10-17T03:00:00")),
+            at("2026-10-17T00:00:00").date()
+        );
+        // The window starting at 22 on Friday is the first on a later day
+        assert_eq!(
+            schedule.next_play_day_start(friday),
+            Some(at("2026-10-23T22:00:00"))
+        );
+    }
+
+    #[tokio::test]
+    async fn daily_limit_holds_past_midnight_in_an_overnight_window() {
+        let dir = TempDir::new("overnight-limit");
+        let (mut context, _) = scheduling_context(&dir, "2026-10-17T01:00:00");
+        dir.write(
+            "config.toml",
+            &config_contents(
+                "max_plays_per_day = 1",
+                r#"
+                weekdays = "fri"
+                start_time = "22:00:00"
+                end_time = "02:00:00"
+                "#,
+            ),
+        );
+        dir.write("play-count", "2026-10-16 1\n");
+        context
+            .write_time(&dir.0.join("next-play"), at("2026-10-17T00:59:00"))
+            .unwrap();
+        context.wake();
+
+        // The play on Friday evening used up the limit for the whole window
+        assert!(!dir.0.join("last-play").exists());
+        let next_play = context.read_time(&dir.0.join("next-play")).unwrap();
+        assert!(
+            (at("2026-10-23T22:10:00")..=at("2026-10-23T23:00:00")).contains(&next_play),
+            "{next_play}"
+        );
+    }
+
+    #[tokio::test]
+    async fn daily_limit_applies_to_fixed_times() {
+        let dir = TempDir::new("fixed-limit");
+        let (mut context, _) = scheduling_context(&dir, "2026-10-12T12:00:01");
+        dir.write(
+            "config.toml",
+            &config_contents(
+                "max_plays_per_day = 1",
+                &format!("{OFFICE_HOURS}\nfixed_times = [\"12:00:00\", \"15:00:00\"]"),
+            ),
+        );
+        dir.write("play-count", "2026-10-12 1\n");
+        context
+            .write_time(&dir.0.join("next-play"), at("2026-10-12T12:00:00"))
+            .unwrap();
+        context.wake();
+
+        // Neither the fixed time now nor the one later today is played
+        assert!(!dir.0.join("last-play").exists());
+        assert_eq!(context.plays_on(at("2026-10-12T00:00:00").date()), 1);
+        let next_play = context.read_time(&dir.0.join("next-play")).unwrap();
+        assert_eq!(next_play.date(), at("2026-10-13T00:00:00").date());
+    }
+}
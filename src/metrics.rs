@@ -0,0 +1,112 @@
+//! Prometheus metrics. These are only collected and served with the `metrics` feature.
+
+use std::net::SocketAddr;
+
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+#[cfg(feature = "metrics")]
+use std::sync::LazyLock;
+#[cfg(feature = "metrics")]
+use {
+    axum::{http::StatusCode, routing::get, Router},
+    prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge},
+    tracing::{error, info},
+};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsConfig {
+    /// Address to serve metrics on, e.g. `"0.0.0.0:9100"`
+    pub bind: SocketAddr,
+}
+
+#[cfg(feature = "metrics")]
+static PLAYS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("speakthing_plays_total", "Number of sounds played").unwrap()
+});
+#[cfg(feature = "metrics")]
+static LAST_PLAY: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "speakthing_last_play_timestamp_seconds",
+        "Time of the last play"
+    )
+    .unwrap()
+});
+#[cfg(feature = "metrics")]
+static NEXT_PLAY: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "speakthing_next_play_timestamp_seconds",
+        "Time of the next scheduled play"
+    )
+    .unwrap()
+});
+#[cfg(feature = "metrics")]
+static RELOADS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "speakthing_config_reloads_total",
+        "Number of times the config has been loaded successfully"
+    )
+    .unwrap()
+});
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub fn record_play(time: DateTime<FixedOffset>) {
+    #[cfg(feature = "metrics")]
+    {
+        PLAYS.inc();
+        LAST_PLAY.set(time.timestamp());
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub fn set_next_play(time: DateTime<FixedOffset>) {
+    #[cfg(feature = "metrics")]
+    NEXT_PLAY.set(time.timestamp());
+}
+
+pub fn record_reload() {
+    #[cfg(feature = "metrics")]
+    RELOADS.inc();
+}
+
+/// Serves the metrics at `/metrics`
+pub async fn serve(config: MetricsConfig) {
+    #[cfg(not(feature = "metrics"))]
+    tracing::warn!(
+        "Metrics are configured on {}, but this build does not include the metrics feature",
+        config.bind
+    );
+
+    #[cfg(feature = "metrics")]
+    {
+        // Metrics are registered on first use, so make sure all of them show up from the start
+        LazyLock::force(&PLAYS);
+        LazyLock::force(&LAST_PLAY);
+        LazyLock::force(&NEXT_PLAY);
+        LazyLock::force(&RELOADS);
+
+        let app = Router::new().route("/metrics", get(metrics));
+
+        let listener = match tokio::net::TcpListener::bind(config.bind).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Could not bind metrics server to {}: {e}", config.bind);
+                return;
+            }
+        };
+
+        info!("Metrics server listening on {}", config.bind);
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Metrics server error: {e}");
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+async fn metrics() -> Result<String, StatusCode> {
+    prometheus::TextEncoder::new()
+        .encode_to_string(&prometheus::gather())
+        .map_err(|e| {
+            error!("Could not encode metrics: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, error, info, warn};
+
+use crate::Command;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MqttConfig {
+    /// Broker to connect to, e.g. `"mqtt://localhost:1883?client_id=speakthing"`
+    pub url: String,
+    /// Plays are published to `<base_topic>/played` and the next scheduled play to `<base_topic>/next_play`.
+    /// Commands are read from `<base_topic>/command`, or from `<base_topic>/cmd/<name>` with any argument as the payload,
+    /// e.g. `0.5` to `<base_topic>/cmd/volume`.
+    pub base_topic: String,
+}
+
+/// Handle for publishing to the broker from the main loop
+#[derive(Clone)]
+pub struct Publisher {
+    client: AsyncClient,
+    base_topic: String,
+}
+
+impl Publisher {
+    /// Publishes a message about a sound that was played, without waiting for it to be sent
+    pub fn played(&self, payload: String) {
+        let topic = format!("{}/played", self.base_topic);
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, false, payload)
+        {
+            warn!("Could not publish play to MQTT: {e}");
+        }
+    }
+
+    /// Publishes the time of the next scheduled play, retained so that new subscribers get it right away
+    pub fn next_play(&self, time: DateTime<FixedOffset>) {
+        let topic = format!("{}/next_play", self.base_topic);
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, true, time.to_rfc3339())
+        {
+            warn!("Could not publish next play to MQTT: {e}");
+        }
+    }
+}
+
+/// Connects to the broker, returning a publisher for the main loop.
+/// Commands received from the broker are forwarded to the main loop from a separate task.
+pub fn start(config: MqttConfig, commands: Sender<Command>) -> anyhow::Result<Publisher> {
+    let options = MqttOptions::parse_url(&config.url)?;
+    let (client, mut event_loop) = AsyncClient::new(options, 16);
+    let command_topic = format!("{}/command", config.base_topic);
+    let cmd_prefix = format!("{}/cmd/", config.base_topic);
+
+    let subscriber = client.clone();
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker");
+                    // Subscriptions don't survive reconnecting, so subscribe on every connect
+                    for topic in [command_topic.clone(), format!("{cmd_prefix}+")] {
+                        if let Err(e) = subscriber.subscribe(&topic, QoS::AtLeastOnce).await {
+                            error!("Could not subscribe to {topic}: {e}");
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let payload = String::from_utf8_lossy(&publish.payload);
+                    let line = match publish.topic.strip_prefix(&cmd_prefix) {
+                        Some(name) => format!("{name} {}", payload.trim()),
+                        None => payload.trim().to_owned(),
+                    };
+                    let Some(command) = Command::parse(&line) else {
+                        warn!("Unknown MQTT command {line:?}");
+                        continue;
+                    };
+                    if commands.send(command).await.is_err() {
+                        // The main loop is gone, so there is nobody left to send commands to
+                        return;
+                    }
+                }
+                Ok(event) => debug!("MQTT event {event:?}"),
+                Err(e) => {
+                    warn!("MQTT connection error, reconnecting: {e}");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+
+    Ok(Publisher {
+        client,
+        base_topic: config.base_topic,
+    })
+}
@@ -0,0 +1,84 @@
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+/// Random number generator for scheduling and picking sounds.
+/// When seeded, its position in the sequence can be saved, so that a restart continues the same sequence.
+pub enum SpeakerRng {
+    /// Seeded from the operating system, unlike `ThreadRng` which can't be sent between threads
+    Entropy(Box<StdRng>),
+    Seeded {
+        seed: u64,
+        rng: Box<ChaCha12Rng>,
+    },
+}
+
+impl SpeakerRng {
+    /// Creates a generator from a seed, continuing from the given position in its sequence
+    pub fn seeded(seed: u64, position: Option<u128>) -> Self {
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        if let Some(position) = position {
+            rng.set_word_pos(position);
+        }
+        SpeakerRng::Seeded {
+            seed,
+            rng: Box::new(rng),
+        }
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        match self {
+            SpeakerRng::Entropy(_) => None,
+            SpeakerRng::Seeded { seed, .. } => Some(*seed),
+        }
+    }
+
+    /// The seed and position in the sequence, as written to the state file, or `None` if not seeded
+    pub fn state(&self) -> Option<String> {
+        match self {
+            SpeakerRng::Entropy(_) => None,
+            SpeakerRng::Seeded { seed, rng } => Some(format!("{seed} {}\n", rng.get_word_pos())),
+        }
+    }
+
+    /// Parses the seed and position written by [`Self::state`]
+    pub fn parse_state(state: &str) -> Option<(u64, u128)> {
+        let (seed, position) = state.trim().split_once(' ')?;
+        Some((seed.parse().ok()?, position.parse().ok()?))
+    }
+}
+
+impl Default for SpeakerRng {
+    fn default() -> Self {
+        SpeakerRng::Entropy(Box::new(StdRng::from_entropy()))
+    }
+}
+
+impl RngCore for SpeakerRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            SpeakerRng::Entropy(rng) => rng.next_u32(),
+            SpeakerRng::Seeded { rng, .. } => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            SpeakerRng::Entropy(rng) => rng.next_u64(),
+            SpeakerRng::Seeded { rng, .. } => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            SpeakerRng::Entropy(rng) => rng.fill_bytes(dest),
+            SpeakerRng::Seeded { rng, .. } => rng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        match self {
+            SpeakerRng::Entropy(rng) => rng.try_fill_bytes(dest),
+            SpeakerRng::Seeded { rng, .. } => rng.try_fill_bytes(dest),
+        }
+    }
+}
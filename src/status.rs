@@ -0,0 +1,217 @@
+use std::{
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset, NaiveTime};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc::Sender, oneshot},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::Command;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StatusConfig {
+    /// Path of the Unix socket to serve the status on, e.g. `"/run/random-speaker/random-speaker.sock"`.
+    /// Defaults to `random-speaker.sock` in `$XDG_RUNTIME_DIR`.
+    /// Only our own user may connect, since anyone who can connect can also control us.
+    path: Option<PathBuf>,
+}
+
+impl StatusConfig {
+    /// Path of the socket, or an error if there is neither a path nor `$XDG_RUNTIME_DIR`
+    pub fn path(&self) -> anyhow::Result<PathBuf> {
+        if let Some(path) = &self.path {
+            return Ok(path.clone());
+        }
+        // There is deliberately no fallback to a shared directory like /tmp
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => Ok(PathBuf::from(dir).join("random-speaker.sock")),
+            None => anyhow::bail!("the status socket has no path, and $XDG_RUNTIME_DIR is not set"),
+        }
+    }
+}
+
+/// A snapshot of the state of the main loop
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    /// Name of the active profile, or `None` for the top-level config
+    pub profile: Option<String>,
+    pub next_play: Option<DateTime<FixedOffset>>,
+    pub seconds_remaining: Option<i64>,
+    pub last_played: Option<PathBuf>,
+    pub paused: bool,
+    /// Factor the master volume is scaled by, as set by the volume command
+    pub volume: f32,
+    pub plays_today: usize,
+    /// The windows sounds may play in today
+    #[serde(default)]
+    pub windows: Vec<Window>,
+    /// The most recently played sounds, latest first
+    #[serde(default)]
+    pub recent_plays: Vec<Play>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Window {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Play {
+    pub path: PathBuf,
+    pub time: DateTime<FixedOffset>,
+}
+
+/// Writes the status as a line of JSON to everyone who connects to the socket without saying anything.
+/// Clients may instead send commands, one per line and in any case, e.g. `PLAY`, `PAUSE` or `VOLUME 0.5`.
+/// Each is answered with a line of its own, `OK` or `ERR` with the reason, and `STATUS` is answered with the status.
+pub async fn serve(config: StatusConfig, commands: Sender<Command>) {
+    let path = match config.path() {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Not serving status: {e}");
+            return;
+        }
+    };
+    let listener = match bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not bind status socket {}: {e}", path.display());
+            return;
+        }
+    };
+
+    info!("Serving status on {}", path.display());
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(respond(stream, commands.clone()));
+            }
+            Err(e) => warn!("Could not accept status connection: {e}"),
+        }
+    }
+}
+
+/// Binds the socket so that only our own user can connect to it
+fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    // A socket left behind by a previous run would keep us from binding, but anything else is not ours to remove
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                anyhow::bail!("another instance is already serving on it");
+            }
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => anyhow::bail!("the path exists and is not a socket"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)) {
+        let _ = std::fs::remove_file(path);
+        anyhow::bail!("could not restrict its permissions: {e}");
+    }
+    Ok(listener)
+}
+
+/// How long a client gets to send a command before it is just given the status
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(200);
+
+async fn respond(stream: UnixStream, commands: Sender<Command>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    // Clients that only want the status may send nothing at all, or close their end right away
+    let first = match tokio::time::timeout(COMMAND_TIMEOUT, lines.next_line()).await {
+        Ok(Ok(Some(line))) => Some(line),
+        Ok(Ok(None)) | Err(_) => None,
+        Ok(Err(e)) => {
+            debug!("Could not read from status connection: {e}");
+            return;
+        }
+    };
+    let Some(mut line) = first else {
+        if let Some(status) = status_line(&commands).await {
+            let _ = writer.write_all(status.as_bytes()).await;
+        }
+        let _ = writer.shutdown().await;
+        return;
+    };
+
+    loop {
+        let reply = match line.trim() {
+            "" => None,
+            status if status.eq_ignore_ascii_case("status") => status_line(&commands).await,
+            command => Some(match Command::parse(command) {
+                Some(command) => match commands.send(command).await {
+                    Ok(()) => "OK\n".to_owned(),
+                    Err(_) => return,
+                },
+                None => format!("ERR invalid command {command:?}\n"),
+            }),
+        };
+        if let Some(reply) = reply {
+            if let Err(e) = writer.write_all(reply.as_bytes()).await {
+                debug!("Could not reply on status connection: {e}");
+                return;
+            }
+        }
+
+        line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Could not read from status connection: {e}");
+                return;
+            }
+        };
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Asks the main loop for its status, as a line of JSON
+async fn status_line(commands: &Sender<Command>) -> Option<String> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    commands.send(Command::Status(reply_tx)).await.ok()?;
+    let status = reply_rx.await.ok()?;
+    match serde_json::to_string(&status) {
+        Ok(json) => Some(json + "\n"),
+        Err(e) => {
+            warn!("Could not serialize status: {e}");
+            None
+        }
+    }
+}
+
+/// Connects to the status socket of a running instance, runs the command if any, and returns the status after it
+pub async fn control(path: &Path, command: Option<&str>) -> anyhow::Result<Status> {
+    let mut stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("could not connect to {}: {e}", path.display()))?;
+    let mut request = String::new();
+    if let Some(command) = command {
+        request += command;
+        request += "\n";
+    }
+    request += "status\n";
+    stream.write_all(request.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    for line in reply.lines() {
+        if let Some(error) = line.strip_prefix("ERR ") {
+            anyhow::bail!("{error}");
+        }
+    }
+    let status = reply.lines().last().unwrap_or_default();
+    Ok(serde_json::from_str(status)?)
+}
@@ -0,0 +1,80 @@
+use tokio::sync::{
+    mpsc::{self, Sender},
+    oneshot,
+};
+use tracing::debug;
+
+use crate::Command;
+
+/// Reads commands from stdin, one per line, forwarding them to the main loop.
+/// Stops when stdin is closed, e.g. when running as a service.
+/// Replies go to stdout, or to stderr with `json`, where stdout is reserved for events.
+pub async fn serve(commands: Sender<Command>, json: bool) {
+    // Tokio's stdin can't be cancelled, so shutting down would wait for the next line on a terminal.
+    // A thread of our own is instead left behind when the process exits.
+    let (line_tx, mut lines) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            match line {
+                Ok(line) => {
+                    if line_tx.blocking_send(line).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    debug!("Could not read from stdin, no longer reading commands from it: {e}");
+                    return;
+                }
+            }
+        }
+        debug!("Stdin closed, no longer reading commands from it");
+    });
+
+    let reply = |text: String| {
+        if json {
+            eprintln!("{text}");
+        } else {
+            println!("{text}");
+        }
+    };
+    while let Some(line) = lines.recv().await {
+        // Commands are case-insensitive, including the ones handled here
+        let line = line.trim().to_ascii_lowercase();
+        let command = match line.as_str() {
+            "" => continue,
+            "next" => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                if commands.send(Command::NextPlay(reply_tx)).await.is_err() {
+                    return;
+                }
+                match reply_rx.await {
+                    Ok(Some((time, remaining))) => reply(format!(
+                        "Next play @ {time}, in {} seconds",
+                        remaining.num_seconds()
+                    )),
+                    Ok(None) => reply("No play scheduled".to_owned()),
+                    Err(_) => return,
+                }
+                continue;
+            }
+            "help" => {
+                reply("Commands: play, next, skip, reschedule, reload, pause, resume, mute, unmute, volume <factor>".to_owned());
+                continue;
+            }
+            name => match Command::parse(name) {
+                Some(command) => command,
+                None => {
+                    reply(format!(
+                        "Unknown command {name:?}, type \"help\" for a list of commands"
+                    ));
+                    continue;
+                }
+            },
+        };
+
+        if commands.send(command).await.is_err() {
+            // The main loop is gone, so there is nobody left to send commands to
+            return;
+        }
+    }
+}
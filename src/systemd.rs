@@ -0,0 +1,32 @@
+//! Notifications to systemd. These do nothing without the `systemd` feature, or when not run by systemd.
+
+use std::time::Duration;
+
+/// Tells systemd that startup is finished
+pub fn ready() {
+    #[cfg(feature = "systemd")]
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        tracing::warn!("Could not notify systemd of readiness: {e}");
+    }
+}
+
+/// How often the watchdog should be notified, or `None` if systemd doesn't expect it
+pub fn watchdog_interval() -> Option<Duration> {
+    #[cfg(feature = "systemd")]
+    {
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) {
+            // Notify twice per timeout, so that a late notification doesn't get us killed
+            return Some(Duration::from_micros(usec) / 2);
+        }
+    }
+    None
+}
+
+/// Tells systemd that we are still alive
+pub fn watchdog() {
+    #[cfg(feature = "systemd")]
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+        tracing::warn!("Could not notify systemd watchdog: {e}");
+    }
+}
@@ -0,0 +1,57 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::PlayReason;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookConfig {
+    /// URL to POST to whenever a sound is played
+    pub url: String,
+    /// Extra headers to send, e.g. `{ Authorization = "Bearer ..." }`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// Body of the request sent for every play
+#[derive(Debug, Serialize)]
+pub struct Play {
+    pub time: DateTime<FixedOffset>,
+    pub path: PathBuf,
+    pub weight: f32,
+    pub reason: PlayReason,
+}
+
+/// How long to wait for the endpoint before giving up on a webhook
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates the client webhooks are sent with
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder().build().unwrap_or_else(|e| {
+        warn!("Could not create the webhook client, using the default one: {e}");
+        reqwest::Client::default()
+    })
+}
+
+/// Sends the webhook in the background, only logging if it fails
+pub fn send(client: &reqwest::Client, config: &WebhookConfig, play: Play) {
+    // Set on every request rather than the client, so that it also holds for the default client
+    let mut request = client.post(&config.url).timeout(TIMEOUT).json(&play);
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+
+    let url = config.url.clone();
+    tokio::spawn(async move {
+        match request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => debug!("Webhook to {url} returned {}", response.status()),
+            Err(e) => warn!("Webhook to {url} failed: {e}"),
+        }
+    });
+}